@@ -0,0 +1,81 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{cache::ROOT_INPUTS, graph::PackageGraph, workspace::Workspace};
+
+/// The packages with files changed since the git ref `since` and every package depending on them.
+/// Uncommitted and untracked files count as changed, a changed root input affects every package.
+pub fn affected(workspace: &Workspace, graph: &PackageGraph, since: &str) -> Result<BTreeSet<usize>, Box<dyn Error>> {
+    let mut files = git_files(&workspace.root, &["diff", "--name-only", "--relative", since, "--"])?;
+    files.extend(git_files(
+        &workspace.root,
+        &["ls-files", "--others", "--exclude-standard"],
+    )?);
+    let dirs: Vec<&Path> = workspace.packages.iter().map(|p| p.dir.as_path()).collect();
+    let changed = changed_packages(&workspace.root, &dirs, &files);
+    Ok(graph.with_dependents(&changed))
+}
+
+/// Maps the `files` relative to `root` to the package whose directory contains them, the innermost
+/// one for nested packages.
+fn changed_packages(root: &Path, dirs: &[&Path], files: &[PathBuf]) -> BTreeSet<usize> {
+    let mut changed = BTreeSet::new();
+    for file in files {
+        if ROOT_INPUTS.iter().any(|input| file == Path::new(input)) {
+            return (0..dirs.len()).collect();
+        }
+        changed.extend(package_of(dirs, &root.join(file)));
+    }
+    changed
+}
+
+/// The package whose directory contains `path`, the innermost one for nested packages.
+pub fn package_of(dirs: &[&Path], path: &Path) -> Option<usize> {
+    (0..dirs.len())
+        .filter(|i| path.starts_with(dirs[*i]))
+        .max_by_key(|i| dirs[*i].components().count())
+}
+
+fn git_files(root: &Path, args: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let output = Command::new("git").args(args).current_dir(root).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git {} failed: {}", args[0], stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::changed_packages;
+
+    #[test]
+    fn test_changed_packages() {
+        let root = Path::new("/mono");
+        let dirs = [
+            Path::new("/mono/packages/a"),
+            Path::new("/mono/packages/a/nested"),
+            Path::new("/mono/packages/ab"),
+        ];
+        let files = |files: &[&str]| files.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            changed_packages(root, &dirs, &files(&["packages/a/nested/src/index.ts", "README.md"])),
+            [1].into()
+        );
+        assert_eq!(
+            changed_packages(root, &dirs, &files(&["packages/ab/x.ts", "packages/a/x.ts"])),
+            [0, 2].into()
+        );
+        assert_eq!(changed_packages(root, &dirs, &files(&["yarn.lock"])), [0, 1, 2].into());
+    }
+}
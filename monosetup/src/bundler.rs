@@ -0,0 +1,480 @@
+use std::{error::Error, path::Path};
+
+use oxc_allocator::Allocator;
+use oxc_ast::ast::{
+    Argument, ArrayExpressionElement, BindingPattern, CallExpression, Expression, ImportDeclaration,
+    ImportDeclarationSpecifier, NewExpression, ObjectExpression, ObjectPropertyKind, VariableDeclarator,
+};
+use oxc_ast_visit::{walk, Visit};
+use oxc_parser::Parser;
+use oxc_span::{GetSpan, SourceType, Span};
+
+use crate::{
+    diagnostics::Diagnostic,
+    node_modules::{update_file, HoistedPackage, Hoisting},
+    workspace::Workspace,
+};
+
+/// A bundler whose configs copy assets with a plugin. Adapters describe where the configs are and
+/// how the copy plugin is configured, the path fixups are the same for every bundler.
+pub trait Bundler: Sync {
+    fn name(&self) -> &'static str;
+    /// The names of its config files at the root of a package
+    fn config_files(&self) -> &'static [&'static str];
+    fn copy_plugin(&self) -> CopyPlugin;
+}
+
+/// How a copy plugin is configured: `plugin({ <list>: [{ <source>: "path", ... }] })`, created
+/// with or without `new`.
+pub struct CopyPlugin {
+    /// The modules exporting the plugin
+    pub modules: &'static [&'static str],
+    /// The option with the copies, an array or a single copy
+    pub list: &'static str,
+    /// The field of a copy with its source path, or an array of them
+    pub source: &'static str,
+}
+
+pub struct Webpack;
+
+impl Bundler for Webpack {
+    fn name(&self) -> &'static str {
+        "webpack"
+    }
+
+    fn config_files(&self) -> &'static [&'static str] {
+        &[
+            "webpack.config.js",
+            "webpack.config.cjs",
+            "webpack.config.mjs",
+            "webpack.config.ts",
+        ]
+    }
+
+    fn copy_plugin(&self) -> CopyPlugin {
+        CopyPlugin {
+            modules: &["copy-webpack-plugin"],
+            list: "patterns",
+            source: "from",
+        }
+    }
+}
+
+pub struct Rollup;
+
+impl Bundler for Rollup {
+    fn name(&self) -> &'static str {
+        "rollup"
+    }
+
+    fn config_files(&self) -> &'static [&'static str] {
+        &[
+            "rollup.config.js",
+            "rollup.config.cjs",
+            "rollup.config.mjs",
+            "rollup.config.ts",
+        ]
+    }
+
+    fn copy_plugin(&self) -> CopyPlugin {
+        CopyPlugin {
+            modules: &["rollup-plugin-copy"],
+            list: "targets",
+            source: "src",
+        }
+    }
+}
+
+/// esbuild has no config file, it is run by a build script.
+pub struct Esbuild;
+
+impl Bundler for Esbuild {
+    fn name(&self) -> &'static str {
+        "esbuild"
+    }
+
+    fn config_files(&self) -> &'static [&'static str] {
+        &[
+            "esbuild.config.js",
+            "esbuild.config.cjs",
+            "esbuild.config.mjs",
+            "esbuild.js",
+            "esbuild.mjs",
+            "build.js",
+            "build.mjs",
+        ]
+    }
+
+    fn copy_plugin(&self) -> CopyPlugin {
+        CopyPlugin {
+            modules: &["esbuild-plugin-copy"],
+            list: "assets",
+            source: "from",
+        }
+    }
+}
+
+pub struct Vite;
+
+impl Bundler for Vite {
+    fn name(&self) -> &'static str {
+        "vite"
+    }
+
+    fn config_files(&self) -> &'static [&'static str] {
+        &[
+            "vite.config.js",
+            "vite.config.cjs",
+            "vite.config.mjs",
+            "vite.config.ts",
+            "vite.config.mts",
+        ]
+    }
+
+    fn copy_plugin(&self) -> CopyPlugin {
+        CopyPlugin {
+            modules: &["vite-plugin-static-copy"],
+            list: "targets",
+            source: "src",
+        }
+    }
+}
+
+pub const BUNDLERS: [&dyn Bundler; 4] = [&Webpack, &Rollup, &Esbuild, &Vite];
+
+/// The bundler a config file at the root of a package belongs to.
+pub fn bundler_of(file_name: &str) -> Option<&'static dyn Bundler> {
+    BUNDLERS.into_iter().find(|b| b.config_files().contains(&file_name))
+}
+
+/// Points the paths into the package's `node_modules` of every bundler config, including the
+/// sources of its copy plugin, to where the dependency is installed after hoisting. Copy sources
+/// that are not a string literal and have no path into `node_modules` are reported, they have to
+/// be updated by hand. Configs that fail to parse are skipped with a warning.
+pub fn update_bundler_configs(workspace: &mut Workspace, hoisting: &Hoisting) -> Result<(), Box<dyn Error>> {
+    let root = workspace.root.clone();
+    for package in workspace.packages.iter_mut() {
+        let hoisted = HoistedPackage::new(package, &root);
+        for file in package.bundler_configs.iter_mut() {
+            let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+            let Some(bundler) = bundler_of(&name) else {
+                continue;
+            };
+            let unresolved = match unresolved_copies(bundler, &file.path, &file.content) {
+                Ok(unresolved) => unresolved,
+                Err(warning) => {
+                    println!("{warning}");
+                    continue;
+                }
+            };
+            for (span, reason) in unresolved {
+                let span = span.start as usize..span.end as usize;
+                // Paths in string literals such as `path.resolve(__dirname, "node_modules/a")` are
+                // rewritten with the other paths into node_modules
+                if file.content[span.clone()].contains("node_modules/") {
+                    continue;
+                }
+                let warning = Diagnostic::warning("Cannot rewrite this copy pattern statically")
+                    .at(&file.path, &file.content, span, reason)
+                    .with_note(
+                        "copies from node_modules must point to where the dependency is installed after hoisting",
+                    );
+                println!("{warning}");
+            }
+            // The copy sources are string literals like the other paths
+            update_file(file, &hoisted, hoisting);
+        }
+    }
+    Ok(())
+}
+
+/// The copies of a bundler config whose source is not a literal, with the reason.
+fn unresolved_copies(bundler: &dyn Bundler, path: &Path, source: &str) -> Result<Vec<(Span, String)>, Diagnostic> {
+    let allocator = Allocator::default();
+    let source_type = SourceType::from_path(path).unwrap_or_default();
+    let parsed = Parser::new(&allocator, source, source_type).parse();
+    if let Some(error) = parsed.errors.first() {
+        let diagnostic = Diagnostic::warning(format!("Skipping the {} config, it failed to parse", bundler.name()));
+        let label = error.labels.as_ref().and_then(|l| l.first());
+        return Err(match label {
+            Some(label) => diagnostic.at(
+                path,
+                source,
+                label.offset()..label.offset() + label.len(),
+                error.to_string(),
+            ),
+            None => diagnostic.in_file(path).with_note(error.to_string()),
+        });
+    }
+    let plugin = bundler.copy_plugin();
+    let mut bindings = PluginBindings {
+        modules: plugin.modules,
+        names: Vec::new(),
+    };
+    bindings.visit_program(&parsed.program);
+    let mut visitor = CopyVisitor {
+        plugin,
+        names: bindings.names,
+        unresolved: Vec::new(),
+    };
+    visitor.visit_program(&parsed.program);
+    Ok(visitor.unresolved)
+}
+
+/// Collects the names the copy plugin is imported or required as.
+struct PluginBindings {
+    modules: &'static [&'static str],
+    names: Vec<String>,
+}
+
+impl<'a> Visit<'a> for PluginBindings {
+    fn visit_variable_declarator(&mut self, it: &VariableDeclarator<'a>) {
+        let mut init = it.init.as_ref();
+        // `require("copy-webpack-plugin").default`
+        if let Some(Expression::StaticMemberExpression(member)) = init {
+            init = Some(&member.object).filter(|_| member.property.name == "default");
+        }
+        if let Some(Expression::CallExpression(call)) = init {
+            let module = match call.arguments.first() {
+                Some(Argument::StringLiteral(module)) => Some(module.value.as_str()),
+                _ => None,
+            };
+            if call.callee.is_specific_id("require") && module.is_some_and(|m| self.modules.contains(&m)) {
+                match &it.id {
+                    BindingPattern::BindingIdentifier(id) => self.names.push(id.name.to_string()),
+                    // `const { copy } = require("esbuild-plugin-copy")`
+                    BindingPattern::ObjectPattern(pattern) => {
+                        for property in pattern.properties.iter() {
+                            if let BindingPattern::BindingIdentifier(id) = &property.value {
+                                self.names.push(id.name.to_string());
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        walk::walk_variable_declarator(self, it);
+    }
+
+    fn visit_import_declaration(&mut self, it: &ImportDeclaration<'a>) {
+        if !self.modules.contains(&it.source.value.as_str()) {
+            return;
+        }
+        for specifier in it.specifiers.iter().flatten() {
+            match specifier {
+                ImportDeclarationSpecifier::ImportDefaultSpecifier(default) => {
+                    self.names.push(default.local.name.to_string())
+                }
+                ImportDeclarationSpecifier::ImportSpecifier(named) => self.names.push(named.local.name.to_string()),
+                ImportDeclarationSpecifier::ImportNamespaceSpecifier(_) => {}
+            }
+        }
+    }
+}
+
+struct CopyVisitor {
+    plugin: CopyPlugin,
+    names: Vec<String>,
+    unresolved: Vec<(Span, String)>,
+}
+
+impl CopyVisitor {
+    fn unresolved(&mut self, span: Span, reason: String) {
+        self.unresolved.push((span, reason));
+    }
+
+    fn plugin(&mut self, callee: &Expression, arguments: &[Argument], span: Span) {
+        let Expression::Identifier(callee) = callee else {
+            return;
+        };
+        if !self.names.iter().any(|n| n == callee.name.as_str()) {
+            return;
+        }
+        match arguments.first() {
+            Some(Argument::ObjectExpression(options)) => self.options(options),
+            _ => self.unresolved(span, "the options are not an object literal".into()),
+        }
+    }
+
+    fn options(&mut self, options: &ObjectExpression) {
+        let list = self.plugin.list;
+        let Some(copies) = property(options, list) else {
+            return self.unresolved(options.span, format!("no `{list}` in the options"));
+        };
+        match copies {
+            Expression::ArrayExpression(array) => {
+                for element in array.elements.iter() {
+                    match element {
+                        ArrayExpressionElement::ObjectExpression(copy) => self.copy(copy),
+                        // A string is the source of a copy without options
+                        element if element.is_expression() => self.source(element.to_expression()),
+                        element => {
+                            self.unresolved(element.span(), "the copy is not an object or string literal".into())
+                        }
+                    }
+                }
+            }
+            Expression::ObjectExpression(copy) => self.copy(copy),
+            copies => self.unresolved(copies.span(), format!("`{list}` is not an array or object literal")),
+        }
+    }
+
+    fn copy(&mut self, copy: &ObjectExpression) {
+        let source = self.plugin.source;
+        match property(copy, source) {
+            Some(Expression::ArrayExpression(sources)) => {
+                for element in sources.elements.iter() {
+                    match element {
+                        element if element.is_expression() => self.source(element.to_expression()),
+                        element => self.unresolved(element.span(), format!("`{source}` is not a string literal")),
+                    }
+                }
+            }
+            Some(path) => self.source(path),
+            None => self.unresolved(copy.span, format!("no `{source}` in the copy")),
+        }
+    }
+
+    fn source(&mut self, path: &Expression) {
+        match path {
+            Expression::StringLiteral(_) => {}
+            Expression::TemplateLiteral(template) if template.expressions.is_empty() => {}
+            path => {
+                let reason = format!("`{}` is not a string literal", self.plugin.source);
+                self.unresolved(path.span(), reason)
+            }
+        }
+    }
+}
+
+/// The value of the property `key` of an object literal.
+fn property<'b, 'a>(object: &'b ObjectExpression<'a>, key: &str) -> Option<&'b Expression<'a>> {
+    object.properties.iter().find_map(|p| match p {
+        ObjectPropertyKind::ObjectProperty(p) if p.key.static_name().as_deref() == Some(key) => Some(&p.value),
+        _ => None,
+    })
+}
+
+impl<'a> Visit<'a> for CopyVisitor {
+    fn visit_new_expression(&mut self, it: &NewExpression<'a>) {
+        self.plugin(&it.callee, &it.arguments, it.span);
+        walk::walk_new_expression(self, it);
+    }
+
+    fn visit_call_expression(&mut self, it: &CallExpression<'a>) {
+        self.plugin(&it.callee, &it.arguments, it.span);
+        walk::walk_call_expression(self, it);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use crate::{node_modules::Hoisting, workspace::Workspace};
+
+    use super::{unresolved_copies, update_bundler_configs, Bundler, Esbuild, Rollup, Vite, Webpack};
+
+    fn parse(bundler: &dyn Bundler, file: &str, config: &str) -> Vec<String> {
+        let unresolved = unresolved_copies(bundler, Path::new(file), config).unwrap();
+        unresolved.into_iter().map(|(_, reason)| reason).collect()
+    }
+
+    #[test]
+    fn test_webpack() {
+        let config = r#"
+const path = require("path");
+const CopyPlugin = require("copy-webpack-plugin");
+const patterns = ["not", "these"];
+module.exports = [
+    {
+        // [nested] arrays and comments before the patterns
+        entry: { main: ["./src/index.ts"] },
+        plugins: [
+            new CopyPlugin({
+                patterns: [
+                    { from: "./node_modules/lokijs/build/lokijs.min.js", to: "[name][ext]" },
+                    `node_modules/a/b.js`,
+                    { from: path.resolve(__dirname, "node_modules/c"), to: "c" },
+                ],
+            }),
+        ],
+    },
+    { plugins: [new CopyPlugin({ patterns })] },
+];
+"#;
+        assert_eq!(
+            parse(&Webpack, "webpack.config.js", config),
+            vec![
+                "`from` is not a string literal",
+                "`patterns` is not an array or object literal"
+            ]
+        );
+        let error = unresolved_copies(&Webpack, Path::new("webpack.config.js"), "new CopyPlugin({").unwrap_err();
+        assert!(error.warning);
+    }
+
+    #[test]
+    fn test_other_bundlers() {
+        let rollup = r#"
+import copy from "rollup-plugin-copy";
+const targets = [];
+export default [
+    { plugins: [copy({ targets: [{ src: ["node_modules/a/a.css", "src/b.css"], dest: "dist" }] })] },
+    { plugins: [copy({ targets })] },
+];
+"#;
+        assert_eq!(parse(&Rollup, "rollup.config.mjs", rollup), vec!["`targets` is not an array or object literal"]);
+
+        let vite = r#"
+import { defineConfig } from "vite";
+import { viteStaticCopy } from "vite-plugin-static-copy";
+export default defineConfig({ plugins: [viteStaticCopy({ targets: [{ src: dir + "/a.wasm", dest: "." }] })] });
+"#;
+        assert_eq!(parse(&Vite, "vite.config.ts", vite), vec!["`src` is not a string literal"]);
+
+        let esbuild = r#"
+const esbuild = require("esbuild");
+const { copy } = require("esbuild-plugin-copy");
+esbuild.build({ plugins: [copy({ assets: { from: ["./node_modules/a/a.js"], to: ["./a"] } })] });
+"#;
+        assert!(parse(&Esbuild, "esbuild.config.js", esbuild).is_empty());
+    }
+
+    #[test]
+    fn test_update_bundler_configs() {
+        let root = std::env::temp_dir().join(format!("monosetup-bundler-{}", std::process::id()));
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write(
+            "packages/a/package.json",
+            r#"{ "name": "a", "version": "1.0.0", "devDependencies": { "lokijs": "^1.5.12" } }"#,
+        );
+        write(
+            "packages/a/webpack.config.js",
+            r#"// copies from node_modules/lokijs
+const CopyPlugin = require("copy-webpack-plugin");
+module.exports = { plugins: [new CopyPlugin({ patterns: [{ from: "./node_modules/lokijs/build/lokijs.min.js" }, `node_modules/lokijs/x.js`] })] };
+"#,
+        );
+        let broken = "new CopyPlugin({ patterns: ['node_modules/lokijs/x.js'] ";
+        write("packages/a/rollup.config.mjs", broken);
+        let mut workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+        update_bundler_configs(&mut workspace, &Hoisting::new(&[]).unwrap()).unwrap();
+        let configs = &workspace.packages[0].bundler_configs;
+        assert_eq!(
+            configs[0].content,
+            r#"// copies from node_modules/lokijs
+const CopyPlugin = require("copy-webpack-plugin");
+module.exports = { plugins: [new CopyPlugin({ patterns: [{ from: "../../node_modules/lokijs/build/lokijs.min.js" }, `../../node_modules/lokijs/x.js`] })] };
+"#
+        );
+        // Skipped with a warning like the other configs
+        assert_eq!(configs[1].content, broken);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
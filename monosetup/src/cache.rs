@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config::BuildConfig, graph::PackageGraph, workspace::Workspace};
+
+/// Files of the monorepo root that affect the build of every package.
+pub const ROOT_INPUTS: [&str; 3] = ["package.json", "yarn.lock", "tsconfig.json"];
+/// The hash of the last successful build of each package.
+const STATE_FILE: &str = ".monosetup/build-state.json";
+/// Cached build outputs, stored by the hash of the build.
+const CACHE_DIR: &str = ".monosetup/cache";
+
+/// Hashes the inputs of every package: its files except `node_modules`, hidden directories, the
+/// build `outputs` and editor temp files, the root inputs and the hashes of the internal packages
+/// it depends on.
+pub fn package_hashes(
+    workspace: &Workspace,
+    graph: &PackageGraph,
+    outputs: &[String],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut root = Sha256::new();
+    for file in ROOT_INPUTS {
+        if let Ok(content) = fs::read(workspace.root.join(file)) {
+            hash_file(&mut root, file, &content);
+        }
+    }
+    let root = root.finalize();
+    let mut hashes = vec![String::new(); graph.names.len()];
+    for i in graph.topological_order()? {
+        let dir = &workspace.packages[i].dir;
+        let mut files = Vec::new();
+        collect_sources(dir, outputs, &mut files)?;
+        files.sort();
+        let mut hasher = Sha256::new();
+        hasher.update(root);
+        for file in files {
+            let relative = file.strip_prefix(dir)?.components();
+            let relative: Vec<_> = relative.map(|c| c.as_os_str().to_string_lossy()).collect();
+            hash_file(&mut hasher, &relative.join("/"), &fs::read(&file)?);
+        }
+        for dependency in graph.dependencies[i].iter() {
+            hasher.update(&hashes[*dependency]);
+        }
+        hashes[i] = format!("{:x}", hasher.finalize());
+    }
+    Ok(hashes)
+}
+
+fn hash_file(hasher: &mut Sha256, path: &str, content: &[u8]) {
+    hasher.update(path);
+    hasher.update([0]);
+    hasher.update((content.len() as u64).to_le_bytes());
+    hasher.update(content);
+}
+
+fn collect_sources(dir: &Path, outputs: &[String], files: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            if !is_ignored(&name, outputs) {
+                collect_sources(&path, outputs, files)?;
+            }
+        } else if !is_temp_file(&name) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks if a directory named `name` holds no sources: `node_modules`, hidden directories and the
+/// build `outputs`.
+pub fn is_ignored(name: &str, outputs: &[String]) -> bool {
+    name == "node_modules" || name.starts_with('.') || outputs.iter().any(|o| o == name)
+}
+
+/// Checks if a file named `name` is a swap, backup or lock file of an editor, or a file monosetup
+/// stages a write in, rather than a source.
+pub fn is_temp_file(name: &str) -> bool {
+    const SUFFIXES: [&str; 6] = [".swp", ".swo", ".swx", "~", ".tmp", ".monosetup-tmp"];
+    // Vim probes whether it can write to a directory with a file named 4913
+    name == "4913"
+        || name == ".DS_Store"
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+        || SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+/// The last successful build of a package.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Build {
+    hash: String,
+    /// The output directories the build left in the package
+    outputs: Vec<String>,
+}
+
+/// Remembers which packages were built with which inputs, and optionally their outputs.
+#[derive(Debug)]
+pub struct BuildCache {
+    root: PathBuf,
+    built: Mutex<BTreeMap<String, Build>>,
+    outputs: Vec<String>,
+    cache_outputs: bool,
+}
+
+impl BuildCache {
+    pub fn load(root: &Path, config: &BuildConfig) -> Result<Self, Box<dyn Error>> {
+        // A state that cannot be read, e.g. of an older version, only means rebuilding
+        let built = fs::read_to_string(root.join(STATE_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Ok(Self {
+            root: root.to_path_buf(),
+            built: Mutex::new(built),
+            outputs: config.outputs.clone(),
+            cache_outputs: config.cache_outputs,
+        })
+    }
+
+    /// Checks if the last successful build of `package` in `dir` had the same inputs and its
+    /// outputs are still there.
+    pub fn is_built(&self, package: &str, dir: &Path, hash: &str) -> bool {
+        let built = self.built.lock().unwrap();
+        built
+            .get(package)
+            .is_some_and(|b| b.hash == hash && b.outputs.iter().all(|o| dir.join(o).is_dir()))
+    }
+
+    /// Replaces the outputs in `dir` with the cached outputs of the build with `hash`, returns
+    /// false if there are none.
+    pub fn restore(&self, dir: &Path, hash: &str) -> Result<bool, Box<dyn Error>> {
+        let cached = self.root.join(CACHE_DIR).join(hash);
+        if !self.cache_outputs || !cached.is_dir() {
+            return Ok(false);
+        }
+        for output in self.outputs.iter() {
+            let target = dir.join(output);
+            if target.exists() {
+                fs::remove_dir_all(&target)?;
+            }
+            if cached.join(output).is_dir() {
+                copy_dir(&cached.join(output), &target)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Records a successful build of `package`, its outputs are cached if enabled.
+    pub fn record(&self, package: &str, dir: &Path, hash: &str) -> Result<(), Box<dyn Error>> {
+        if self.cache_outputs {
+            let cached = self.root.join(CACHE_DIR).join(hash);
+            if cached.exists() {
+                fs::remove_dir_all(&cached)?;
+            }
+            fs::create_dir_all(&cached)?;
+            for output in self.outputs.iter().filter(|o| dir.join(o).is_dir()) {
+                copy_dir(&dir.join(output), &cached.join(output))?;
+            }
+        }
+        let build = Build {
+            hash: hash.to_string(),
+            outputs: self.outputs.iter().filter(|o| dir.join(o).is_dir()).cloned().collect(),
+        };
+        self.built.lock().unwrap().insert(package.to_string(), build);
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = self.root.join(STATE_FILE);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, serde_json::to_string_pretty(&*self.built.lock().unwrap())?)?;
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::{config::BuildConfig, graph::PackageGraph, workspace::Workspace};
+
+    use super::{package_hashes, BuildCache};
+
+    #[test]
+    fn test_hashes_and_restore() {
+        let root = std::env::temp_dir().join(format!("monosetup-cache-{}", std::process::id()));
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("packages/a/package.json", r#"{ "name": "a", "version": "1.0.0" }"#);
+        write("packages/a/src/index.ts", "export const a = 1;");
+        write(
+            "packages/b/package.json",
+            r#"{ "name": "b", "version": "1.0.0", "dependencies": { "a": "1.0.0" } }"#,
+        );
+        let config = BuildConfig {
+            cache_outputs: true,
+            ..Default::default()
+        };
+        let hashes = || {
+            let workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+            package_hashes(&workspace, &PackageGraph::new(&workspace), &config.outputs).unwrap()
+        };
+        let before = hashes();
+        // Outputs do not change the hash, sources change the hash of the package and its dependents
+        write("packages/a/dist/index.js", "exports.a = 1;");
+        write("packages/a/src/.index.ts.swp", "");
+        write("packages/a/src/index.ts~", "");
+        assert_eq!(hashes(), before);
+        write("packages/a/src/index.ts", "export const a = 2;");
+        let after = hashes();
+        assert!(after[0] != before[0] && after[1] != before[1]);
+
+        let cache = BuildCache::load(&root, &config).unwrap();
+        cache.record("a", &root.join("packages/a"), &after[0]).unwrap();
+        cache.save().unwrap();
+        let cache = BuildCache::load(&root, &config).unwrap();
+        let a = root.join("packages/a");
+        assert!(cache.is_built("a", &a, &after[0]) && !cache.is_built("a", &a, &before[0]));
+        // A deleted output is restored from the cache, or rebuilt without one
+        fs::remove_dir_all(root.join("packages/a/dist")).unwrap();
+        assert!(!cache.is_built("a", &a, &after[0]));
+        let uncached = BuildCache::load(&root, &BuildConfig::default()).unwrap();
+        assert!(!uncached.is_built("a", &a, &after[0]) && !uncached.restore(&a, &after[0]).unwrap());
+        assert!(cache.restore(&root.join("packages/a"), &after[0]).unwrap());
+        assert!(cache.is_built("a", &a, &after[0]));
+        assert_eq!(
+            fs::read_to_string(root.join("packages/a/dist/index.js")).unwrap(),
+            "exports.a = 1;"
+        );
+        assert!(!cache.restore(&root.join("packages/a"), &before[0]).unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
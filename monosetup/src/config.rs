@@ -0,0 +1,186 @@
+use std::{error::Error, fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::diagnostics::Diagnostic;
+
+/// Name of the user editable configuration in the monorepo root.
+pub const CONFIG_FILE: &str = "monosetup.json";
+
+/// Configuration of monosetup, read from `monosetup.json` (JSON5) in the monorepo root.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    /// Glob patterns of the package directories relative to the monorepo root
+    pub workspaces: Vec<String>,
+    pub hoist: HoistConfig,
+    /// Link internal dependencies with the `workspace:` protocol instead of their exact version,
+    /// requires a package manager supporting it.
+    pub workspace_protocol: bool,
+    /// Template of the root package.json, `workspaces` and the hoisted devDependencies are added
+    /// to it. Fields of the root package.json that are not in the template are kept.
+    pub root_package: Option<serde_json::Map<String, serde_json::Value>>,
+    pub build: BuildConfig,
+    pub notest: NotestConfig,
+}
+
+impl Config {
+    /// The configured workspace patterns, `packages/*` if there are none.
+    pub fn workspace_patterns(&self) -> Vec<String> {
+        if self.workspaces.is_empty() {
+            vec!["packages/*".into()]
+        } else {
+            self.workspaces.clone()
+        }
+    }
+
+    /// Loads the config from `path`, a missing file results in the default config.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)?;
+        json5::from_str(&content).map_err(|json5::Error::Message { msg, location }| {
+            let diagnostic = Diagnostic::new("Failed to parse the monosetup config");
+            match location {
+                Some(l) => diagnostic.at_line(path, &content, l.line, l.column, msg),
+                None => diagnostic.in_file(path).with_note(msg),
+            }
+            .into()
+        })
+    }
+}
+
+/// Controls the build cache of the `build` command.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BuildConfig {
+    /// Directories a build writes to in the package, they are not part of the hash
+    pub outputs: Vec<String>,
+    /// Keep the outputs of successful builds in `.monosetup/cache` and restore them instead of
+    /// building a package again
+    pub cache_outputs: bool,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            outputs: vec!["dist".into(), "lib".into(), "lib-web".into()],
+            cache_outputs: false,
+        }
+    }
+}
+
+/// Decides which steps of the package scripts run tests, they are left out of the generated
+/// `:notest` scripts.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NotestConfig {
+    /// Glob patterns of script names that run tests
+    pub scripts: Vec<String>,
+    /// Programs that run tests
+    pub commands: Vec<String>,
+}
+
+impl Default for NotestConfig {
+    fn default() -> Self {
+        Self {
+            scripts: vec!["test".into(), "test:*".into(), "*:test".into()],
+            commands: ["mocha", "jest", "karma", "jasmine", "vitest", "ava", "nyc", "c8"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// Decides which devDependencies are moved from the packages into the monorepo root.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HoistConfig {
+    pub policy: HoistPolicy,
+    /// Dependencies that are hoisted regardless of the policy.
+    pub always: Vec<String>,
+    /// Dependencies that are never hoisted.
+    pub never: Vec<String>,
+    pub nohoist: NohoistConfig,
+}
+
+/// Controls the `nohoist` list of the root workspaces, dependencies in it are installed into the
+/// node_modules of every package using them.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NohoistConfig {
+    /// Detect dependencies that conflict when they are hoisted
+    pub detect: bool,
+    /// Patterns that are always added
+    pub patterns: Vec<String>,
+    /// Dependencies the detection never adds
+    pub ignore: Vec<String>,
+}
+
+impl Default for NohoistConfig {
+    fn default() -> Self {
+        Self {
+            detect: true,
+            patterns: Vec::new(),
+            ignore: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum HoistPolicy {
+    /// Hoist a dependency used by every package.
+    #[default]
+    All,
+    /// Hoist a dependency used by at least this many packages.
+    AtLeast(usize),
+    /// Hoist a dependency used by at least this percentage of the packages.
+    Percent(f64),
+}
+
+impl HoistConfig {
+    /// Returns why `name` is hoisted if it is used by `used` out of `total` packages.
+    pub fn reason(&self, name: &str, used: usize, total: usize) -> Option<String> {
+        if self.never.iter().any(|n| n == name) {
+            return None;
+        }
+        if self.always.iter().any(|n| n == name) {
+            return Some("always hoisted by config".into());
+        }
+        let hoist = match self.policy {
+            HoistPolicy::All => used >= total,
+            HoistPolicy::AtLeast(n) => used >= n,
+            HoistPolicy::Percent(p) => used as f64 * 100.0 >= p * total as f64,
+        };
+        hoist.then(|| match self.policy {
+            HoistPolicy::All => format!("used by all {total} packages"),
+            HoistPolicy::AtLeast(n) => format!("used by {used}/{total} packages, at least {n} required"),
+            HoistPolicy::Percent(p) => format!("used by {used}/{total} packages, at least {p}% required"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, HoistPolicy};
+
+    #[test]
+    fn test_hoist_policy() {
+        let config: Config = json5::from_str(
+            r#"{
+            // hoist anything used by more than half of the packages
+            hoist: { policy: { percent: 50 }, always: ["typescript"], never: ["webpack"] },
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(config.hoist.policy, HoistPolicy::Percent(50.0));
+        assert!(config.hoist.reason("mocha", 3, 5).is_some());
+        assert!(config.hoist.reason("mocha", 2, 5).is_none());
+        assert!(config.hoist.reason("typescript", 1, 5).is_some());
+        assert!(config.hoist.reason("webpack", 5, 5).is_none());
+        let config: Config = json5::from_str(r#"{ hoist: { policy: "all" } }"#).unwrap();
+        assert!(config.hoist.reason("mocha", 4, 5).is_none());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+/// An error that points at the offending location of a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Warnings are printed and do not stop the setup
+    pub warning: bool,
+    pub message: String,
+    pub location: Option<Box<Location>>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, counted in characters
+    pub column: usize,
+    /// The source line and the number of characters to underline in it
+    pub snippet: Option<(String, usize)>,
+    pub label: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            warning: false,
+            message: message.into(),
+            location: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            warning: true,
+            ..Self::new(message)
+        }
+    }
+
+    /// Points the diagnostic at the byte range `span` of `source`, which is the content of `file`.
+    pub fn at(mut self, file: &Path, source: &str, span: Range<usize>, label: impl Into<String>) -> Self {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        let column = source[line_start..start].chars().count() + 1;
+        let len = source[start..span.end.clamp(start, line_end)].chars().count().max(1);
+        self.location = Some(Box::new(Location {
+            file: file.to_path_buf(),
+            line: source[..start].matches('\n').count() + 1,
+            column,
+            snippet: Some((line.to_string(), len)),
+            label: Some(label.into()).filter(|l: &String| !l.is_empty()),
+        }));
+        self
+    }
+
+    /// Points the diagnostic at a 1-based line and column of `source`.
+    pub fn at_line(self, file: &Path, source: &str, line: usize, column: usize, label: impl Into<String>) -> Self {
+        let line_start: usize = source
+            .split_inclusive('\n')
+            .take(line.saturating_sub(1))
+            .map(str::len)
+            .sum();
+        let offset = source[line_start..]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(source.len(), |(i, _)| line_start + i);
+        self.at(file, source, offset..offset, label)
+    }
+
+    /// Names the file without pointing at a line, for errors that concern the whole file.
+    pub fn in_file(mut self, file: &Path) -> Self {
+        self.location = Some(Box::new(Location {
+            file: file.to_path_buf(),
+            line: 0,
+            column: 0,
+            snippet: None,
+            label: None,
+        }));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = if self.warning { "warning" } else { "error" };
+        write!(f, "{severity}: {}", self.message)?;
+        let gutter = match self.location.as_deref() {
+            Some(Location {
+                file,
+                line,
+                column,
+                snippet: Some((source, len)),
+                label,
+            }) => {
+                let gutter = " ".repeat(line.to_string().len());
+                write!(f, "\n{gutter}--> {}:{line}:{column}", file.display())?;
+                write!(f, "\n{gutter} |\n{line} | {source}\n{gutter} | ")?;
+                write!(f, "{}{}", " ".repeat(column - 1), "^".repeat(*len))?;
+                if let Some(label) = label {
+                    write!(f, " {label}")?;
+                }
+                gutter
+            }
+            Some(Location { file, .. }) => {
+                write!(f, "\n --> {}", file.display())?;
+                " ".into()
+            }
+            None => String::new(),
+        };
+        if !self.notes.is_empty() {
+            write!(f, "\n{gutter} |")?;
+        }
+        for note in self.notes.iter() {
+            write!(f, "\n{gutter} = note: {note}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostic {}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::Diagnostic;
+
+    #[test]
+    fn test_render() {
+        let source = "{\n  \"name\": \"a\",\n  \"version\": \"foo\"\n}\n";
+        let start = source.find("\"foo\"").unwrap();
+        let diagnostic = Diagnostic::new("invalid version `foo`")
+            .at(Path::new("package.json"), source, start..start + 5, "version")
+            .with_note("a version is of format x.x.x");
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: invalid version `foo`\n --> package.json:3:14\n  |\n3 |   \"version\": \"foo\"\n  |              ^^^^^ version\n  |\n  = note: a version is of format x.x.x"
+        );
+        let at_line = Diagnostic::new("x").at_line(Path::new("package.json"), source, 3, 14, "");
+        let expected = diagnostic.location.map(|l| super::Location {
+            label: None,
+            snippet: Some((l.snippet.clone().unwrap().0, 1)),
+            ..*l
+        });
+        assert_eq!(at_line.location.map(|l| *l), expected);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+};
+
+use clap::ValueEnum;
+use glob::Pattern;
+use serde_json::{json, Value};
+
+use crate::{mono::DependencyKind, workspace::Workspace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+/// The dependencies between the packages of the workspace, packages are referred to by their
+/// index in `names`, which follows the order of the workspace.
+#[derive(Debug, Clone)]
+pub struct PackageGraph {
+    pub names: Vec<String>,
+    /// The internal packages each package depends on, with any kind of dependency
+    pub dependencies: Vec<BTreeSet<usize>>,
+}
+
+impl PackageGraph {
+    pub fn new(workspace: &Workspace) -> Self {
+        let names: Vec<String> = workspace
+            .packages
+            .iter()
+            .map(|p| p.manifest.manifest.name.clone())
+            .collect();
+        let index: HashMap<&str, usize> = names.iter().enumerate().map(|(i, n)| (n.as_str(), i)).collect();
+        let dependencies = workspace
+            .packages
+            .iter()
+            .map(|p| {
+                DependencyKind::ALL
+                    .iter()
+                    .filter_map(|kind| p.manifest.manifest.dependencies(*kind))
+                    .flat_map(|deps| deps.keys())
+                    .filter_map(|dep| index.get(dep.as_str()).copied())
+                    .collect()
+            })
+            .collect();
+        Self { names, dependencies }
+    }
+
+    /// The packages whose name or directory in `members` matches one of the glob `filters`, every
+    /// package if there are none.
+    pub fn filter(&self, members: &[String], filters: &[String]) -> Result<BTreeSet<usize>, Box<dyn Error>> {
+        if filters.is_empty() {
+            return Ok((0..self.names.len()).collect());
+        }
+        let mut selected = BTreeSet::new();
+        for filter in filters {
+            let pattern = Pattern::new(filter).map_err(|e| format!("Invalid filter `{filter}`: {e}"))?;
+            let matches: Vec<usize> = (0..self.names.len())
+                .filter(|i| pattern.matches(&self.names[*i]) || members.get(*i).is_some_and(|m| pattern.matches(m)))
+                .collect();
+            if matches.is_empty() {
+                return Err(format!("No package matches the filter `{filter}`").into());
+            }
+            selected.extend(matches);
+        }
+        Ok(selected)
+    }
+
+    /// Adds every package the `selected` packages depend on, directly or indirectly.
+    pub fn with_dependencies(&self, selected: &BTreeSet<usize>) -> BTreeSet<usize> {
+        self.closure(selected, |i| self.dependencies[i].iter().copied().collect())
+    }
+
+    /// Adds every package depending on the `selected` packages, directly or indirectly.
+    pub fn with_dependents(&self, selected: &BTreeSet<usize>) -> BTreeSet<usize> {
+        self.closure(selected, |i| self.dependents(i).collect())
+    }
+
+    fn closure(&self, selected: &BTreeSet<usize>, next: impl Fn(usize) -> Vec<usize>) -> BTreeSet<usize> {
+        let mut result = selected.clone();
+        let mut queue: Vec<usize> = selected.iter().copied().collect();
+        while let Some(i) = queue.pop() {
+            queue.extend(next(i).into_iter().filter(|n| result.insert(*n)));
+        }
+        result
+    }
+
+    /// The same packages without dependencies between them, to run them regardless of their order.
+    pub fn unordered(&self) -> Self {
+        Self {
+            names: self.names.clone(),
+            dependencies: vec![BTreeSet::new(); self.names.len()],
+        }
+    }
+
+    /// The packages depending on package `i`.
+    pub fn dependents(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.names.len()).filter(move |p| self.dependencies[*p].contains(&i))
+    }
+
+    /// Orders the packages so each one comes after its dependencies, fails with the path of a
+    /// circular dependency.
+    pub fn topological_order(&self) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut remaining: Vec<usize> = self.dependencies.iter().map(BTreeSet::len).collect();
+        let mut ready: BTreeSet<usize> = (0..self.names.len()).filter(|i| remaining[*i] == 0).collect();
+        let mut order = Vec::with_capacity(self.names.len());
+        while let Some(i) = ready.pop_first() {
+            order.push(i);
+            for dependent in self.dependents(i) {
+                remaining[dependent] -= 1;
+                if remaining[dependent] == 0 {
+                    ready.insert(dependent);
+                }
+            }
+        }
+        if order.len() == self.names.len() {
+            return Ok(order);
+        }
+        let cycle = self.find_cycle(&order);
+        let path: Vec<&str> = cycle.iter().map(|i| self.names[*i].as_str()).collect();
+        Err(format!("Circular dependency between packages: {}", path.join(" -> ")).into())
+    }
+
+    /// Follows the dependencies of the packages missing in `ordered` until one repeats.
+    fn find_cycle(&self, ordered: &[usize]) -> Vec<usize> {
+        let start = (0..self.names.len()).find(|i| !ordered.contains(i)).unwrap();
+        let mut path = vec![start];
+        loop {
+            let current = *path.last().unwrap();
+            let next = *self.dependencies[current]
+                .iter()
+                .find(|d| !ordered.contains(d))
+                .unwrap();
+            if let Some(pos) = path.iter().position(|p| *p == next) {
+                path.drain(..pos);
+                path.push(next);
+                return path;
+            }
+            path.push(next);
+        }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> Result<String, Box<dyn Error>> {
+        let order = self.topological_order()?;
+        let ordered: Vec<&str> = order.iter().map(|i| self.names[*i].as_str()).collect();
+        let edges = self
+            .dependencies
+            .iter()
+            .enumerate()
+            .flat_map(|(from, deps)| deps.iter().map(move |to| (from, *to)));
+        Ok(match format {
+            GraphFormat::Dot => {
+                let mut dot = String::from("digraph packages {\n");
+                dot.push_str(&format!("  // build order: {}\n", ordered.join(", ")));
+                for name in self.names.iter() {
+                    dot.push_str(&format!("  \"{name}\";\n"));
+                }
+                for (from, to) in edges {
+                    dot.push_str(&format!("  \"{}\" -> \"{}\";\n", self.names[from], self.names[to]));
+                }
+                dot + "}\n"
+            }
+            GraphFormat::Mermaid => {
+                let mut mermaid = String::from("graph TD\n");
+                mermaid.push_str(&format!("  %% build order: {}\n", ordered.join(", ")));
+                for (i, name) in self.names.iter().enumerate() {
+                    mermaid.push_str(&format!("  p{i}[\"{name}\"]\n"));
+                }
+                for (from, to) in edges {
+                    mermaid.push_str(&format!("  p{from} --> p{to}\n"));
+                }
+                mermaid
+            }
+            GraphFormat::Json => {
+                let packages: serde_json::Map<String, Value> = self
+                    .names
+                    .iter()
+                    .zip(self.dependencies.iter())
+                    .map(|(name, deps)| {
+                        let deps: Vec<&str> = deps.iter().map(|d| self.names[*d].as_str()).collect();
+                        (name.clone(), json!(deps))
+                    })
+                    .collect();
+                serde_json::to_string_pretty(&json!({ "packages": packages, "order": ordered }))? + "\n"
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::{GraphFormat, PackageGraph};
+
+    fn graph(dependencies: &[&[usize]]) -> PackageGraph {
+        PackageGraph {
+            names: (0..dependencies.len()).map(|i| format!("p{i}")).collect(),
+            dependencies: dependencies
+                .iter()
+                .map(|d| d.iter().copied().collect::<BTreeSet<_>>())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let g = graph(&[&[1, 2], &[2], &[], &[0]]);
+        assert_eq!(g.topological_order().unwrap(), vec![2, 1, 0, 3]);
+        assert_eq!(
+            g.render(GraphFormat::Mermaid).unwrap(),
+            "graph TD\n  %% build order: p2, p1, p0, p3\n  p0[\"p0\"]\n  p1[\"p1\"]\n  p2[\"p2\"]\n  p3[\"p3\"]\n  p0 --> p1\n  p0 --> p2\n  p1 --> p2\n  p3 --> p0\n"
+        );
+        let members: Vec<String> = (0..4).map(|i| format!("packages/p{i}")).collect();
+        let selected = g.filter(&members, &["p1".into()]).unwrap();
+        assert_eq!(g.with_dependencies(&selected), [1, 2].into());
+        assert_eq!(g.with_dependents(&selected), [0, 1, 3].into());
+        assert_eq!(g.filter(&members, &["packages/p[23]".into()]).unwrap(), [2, 3].into());
+        assert!(g.filter(&members, &["q*".into()]).is_err());
+        let g = graph(&[&[], &[2], &[3], &[1]]);
+        assert_eq!(
+            g.topological_order().unwrap_err().to_string(),
+            "Circular dependency between packages: p1 -> p2 -> p3 -> p1"
+        );
+    }
+}
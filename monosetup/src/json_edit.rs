@@ -0,0 +1,481 @@
+use std::{error::Error, fmt::Display, ops::Range};
+
+use serde::Serialize;
+use serde_json::{ser::PrettyFormatter, Value};
+
+/// A JSON parse error with the byte offset it occured at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at line {} column {}", self.message, self.line, self.column)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug)]
+enum Node {
+    Object {
+        span: Range<usize>,
+        members: Vec<Member>,
+    },
+    Array {
+        span: Range<usize>,
+    },
+    Scalar {
+        span: Range<usize>,
+    },
+}
+
+impl Node {
+    fn span(&self) -> Range<usize> {
+        match self {
+            Node::Object { span, .. } | Node::Array { span } | Node::Scalar { span } => span.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    key: String,
+    key_span: Range<usize>,
+    value: Node,
+}
+
+/// A JSON document that is edited in place, everything that is not touched by an edit keeps its
+/// formatting: key order, indentation, comments and the final newline.
+#[derive(Debug)]
+pub struct JsonDocument {
+    text: String,
+    indent: String,
+    /// Single line objects have a space inside their braces, `{ "a": 1 }`
+    padded: bool,
+    root: Node,
+}
+
+impl JsonDocument {
+    /// Parses a JSON document, comments and trailing commas are accepted.
+    pub fn parse(text: impl Into<String>) -> Result<Self, ParseError> {
+        let text = text.into();
+        let root = Parser::new(&text).parse_document()?;
+        let indent = detect_indent(&text);
+        let padded = !text.contains("{\"");
+        Ok(Self {
+            text,
+            indent,
+            padded,
+            root,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The byte range of the value at `path`, or of the deepest value along it that exists.
+    pub fn span(&self, path: &[impl AsRef<str>]) -> Range<usize> {
+        self.find_deepest(path).1.span()
+    }
+
+    /// Sets the value at `path`, missing objects along the path are created.
+    pub fn set(&mut self, path: &[impl AsRef<str>], value: &Value) {
+        let (depth, node) = self.find_deepest(path);
+        let span = node.span();
+        if depth == path.len() {
+            let current: Option<Value> = serde_json::from_str(&self.text[span.clone()]).ok();
+            if current.as_ref() != Some(value) {
+                let indent = self.line_indent(span.start).to_string();
+                let formatted = self.format(value, &indent, self.is_multiline(span.clone()));
+                self.replace(span, &formatted);
+            }
+            return;
+        }
+        let mut nested = value.clone();
+        for key in path[depth + 1..].iter().rev() {
+            let mut object = serde_json::Map::new();
+            object.insert(key.as_ref().to_string(), nested);
+            nested = Value::Object(object);
+        }
+        let key = path[depth].as_ref();
+        let Node::Object { members, .. } = node else {
+            // A value that is not an object is replaced by one holding the rest of the path
+            let mut object = serde_json::Map::new();
+            object.insert(key.to_string(), nested);
+            let indent = self.line_indent(span.start).to_string();
+            let formatted = self.format(&Value::Object(object), &indent, true);
+            self.replace(span, &formatted);
+            return;
+        };
+        let key = serde_json::to_string(key).unwrap();
+        match members.last() {
+            Some(last) => {
+                let at = last.value.span().end;
+                let edit = if self.is_multiline(span) {
+                    let indent = self.line_indent(last.key_span.start).to_string();
+                    format!(",\n{indent}{key}: {}", self.format(&nested, &indent, true))
+                } else {
+                    format!(", {key}: {}", self.format(&nested, "", false))
+                };
+                self.replace(at..at, &edit);
+            }
+            // An empty object of a single line document stays on that line
+            None if !self.text.trim_end().contains('\n') => {
+                let pad = if self.padded { " " } else { "" };
+                let edit = format!("{pad}{key}: {}{pad}", self.format(&nested, "", false));
+                self.replace(span.start + 1..span.end - 1, &edit);
+            }
+            None => {
+                let parent = self.line_indent(span.start).to_string();
+                let child = format!("{parent}{}", self.indent);
+                let edit = format!("\n{child}{key}: {}\n{parent}", self.format(&nested, &child, true));
+                self.replace(span.start + 1..span.end - 1, &edit);
+            }
+        }
+    }
+
+    /// Removes the member at `path`, returns false if it does not exist.
+    pub fn remove(&mut self, path: &[impl AsRef<str>]) -> bool {
+        let Some((key, parent)) = path.split_last() else {
+            return false;
+        };
+        let (depth, Node::Object { span, members }) = self.find_deepest(parent) else {
+            return false;
+        };
+        if depth != parent.len() {
+            return false;
+        }
+        let Some(index) = members.iter().position(|m| m.key == key.as_ref()) else {
+            return false;
+        };
+        let range = if members.len() == 1 {
+            span.start + 1..span.end - 1
+        } else if index == members.len() - 1 {
+            members[index - 1].value.span().end..members[index].value.span().end
+        } else {
+            members[index].key_span.start..members[index + 1].key_span.start
+        };
+        self.replace(range, "");
+        true
+    }
+
+    /// Applies the difference between `old` and `new` to the document, `old` is expected to be
+    /// the value of the document.
+    pub fn update(&mut self, old: &Value, new: &Value) {
+        self.update_at(&mut Vec::new(), old, new);
+    }
+
+    fn update_at(&mut self, path: &mut Vec<String>, old: &Value, new: &Value) {
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => {
+                for key in old.keys().filter(|k| !new.contains_key(*k)) {
+                    path.push(key.clone());
+                    self.remove(path);
+                    path.pop();
+                }
+                for (key, value) in new {
+                    path.push(key.clone());
+                    match old.get(key) {
+                        Some(old) => self.update_at(path, old, value),
+                        None => self.set(path, value),
+                    }
+                    path.pop();
+                }
+            }
+            (old, new) if old != new => self.set(path, new),
+            _ => {}
+        }
+    }
+
+    fn find_deepest(&self, path: &[impl AsRef<str>]) -> (usize, &Node) {
+        let mut node = &self.root;
+        for (depth, key) in path.iter().enumerate() {
+            let Node::Object { members, .. } = node else {
+                return (depth, node);
+            };
+            match members.iter().rev().find(|m| m.key == key.as_ref()) {
+                Some(member) => node = &member.value,
+                None => return (depth, node),
+            }
+        }
+        (path.len(), node)
+    }
+
+    fn replace(&mut self, range: Range<usize>, with: &str) {
+        self.text.replace_range(range, with);
+        self.root = Parser::new(&self.text)
+            .parse_document()
+            .expect("edits keep the document valid");
+    }
+
+    fn is_multiline(&self, span: Range<usize>) -> bool {
+        self.text[span].contains('\n')
+    }
+
+    fn line_indent(&self, offset: usize) -> &str {
+        let start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line = &self.text[start..];
+        &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+    }
+
+    fn format(&self, value: &Value, indent: &str, multiline: bool) -> String {
+        if !multiline {
+            return inline(value, self.padded);
+        }
+        let mut out = Vec::new();
+        let mut serializer =
+            serde_json::Serializer::with_formatter(&mut out, PrettyFormatter::with_indent(self.indent.as_bytes()));
+        value.serialize(&mut serializer).unwrap();
+        String::from_utf8(out).unwrap().replace('\n', &format!("\n{indent}"))
+    }
+}
+
+/// `value` on a single line, spaced like hand written JSON: `{ "a": [1, 2], "b": true }`.
+fn inline(value: &Value, padded: bool) -> String {
+    match value {
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(|v| inline(v, padded)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(members) if !members.is_empty() => {
+            let members: Vec<String> = members
+                .iter()
+                .map(|(k, v)| format!("{}: {}", Value::from(k.as_str()), inline(v, padded)))
+                .collect();
+            let pad = if padded { " " } else { "" };
+            format!("{{{pad}{}{pad}}}", members.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// The indentation unit of the first indented line, two spaces if there is none.
+fn detect_indent(text: &str) -> String {
+    text.lines()
+        .map(|l| &l[..l.len() - l.trim_start_matches([' ', '\t']).len()])
+        .find(|i| !i.is_empty())
+        .unwrap_or("  ")
+        .to_string()
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            bytes: text.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn parse_document(mut self) -> Result<Node, ParseError> {
+        self.skip_trivia()?;
+        let node = self.parse_value()?;
+        self.skip_trivia()?;
+        if self.pos != self.bytes.len() {
+            return Err(self.error("Unexpected content after the JSON value"));
+        }
+        Ok(node)
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        let before = &self.text[..self.pos.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        ParseError {
+            offset: self.pos,
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_trivia(&mut self) -> Result<(), ParseError> {
+        loop {
+            match (self.peek(), self.bytes.get(self.pos + 1)) {
+                (Some(c), _) if c.is_ascii_whitespace() => self.pos += 1,
+                (Some(b'/'), Some(b'/')) => {
+                    self.pos = self.text[self.pos..].find('\n').map_or(self.bytes.len(), |i| self.pos + i);
+                }
+                (Some(b'/'), Some(b'*')) => {
+                    let Some(end) = self.text[self.pos + 2..].find("*/") else {
+                        return Err(self.error("Unterminated comment"));
+                    };
+                    self.pos += end + 4;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Node, ParseError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                loop {
+                    self.skip_trivia()?;
+                    if self.peek() == Some(b'}') {
+                        break;
+                    }
+                    let key_start = self.pos;
+                    let key = self.parse_string()?;
+                    let key_span = key_start..self.pos;
+                    self.skip_trivia()?;
+                    if self.peek() != Some(b':') {
+                        return Err(self.error("Expected `:`"));
+                    }
+                    self.pos += 1;
+                    self.skip_trivia()?;
+                    let value = self.parse_value()?;
+                    members.push(Member { key, key_span, value });
+                    if !self.parse_separator(b'}')? {
+                        break;
+                    }
+                }
+                self.pos += 1;
+                Ok(Node::Object {
+                    span: start..self.pos,
+                    members,
+                })
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                loop {
+                    self.skip_trivia()?;
+                    if self.peek() == Some(b']') {
+                        break;
+                    }
+                    self.parse_value()?;
+                    if !self.parse_separator(b']')? {
+                        break;
+                    }
+                }
+                self.pos += 1;
+                Ok(Node::Array { span: start..self.pos })
+            }
+            Some(b'"') => {
+                self.parse_string()?;
+                Ok(Node::Scalar { span: start..self.pos })
+            }
+            Some(_) => {
+                let len = self.text[start..]
+                    .find(|c: char| c.is_whitespace() || matches!(c, ',' | '}' | ']' | '/'))
+                    .unwrap_or(self.text.len() - start);
+                if serde_json::from_str::<Value>(&self.text[start..start + len]).is_err() {
+                    return Err(self.error("Expected a JSON value"));
+                }
+                self.pos += len;
+                Ok(Node::Scalar { span: start..self.pos })
+            }
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    /// Consumes the `,` after an element, returns false if `close` follows instead.
+    fn parse_separator(&mut self, close: u8) -> Result<bool, ParseError> {
+        self.skip_trivia()?;
+        match self.peek() {
+            Some(b',') => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(c) if c == close => Ok(false),
+            _ => Err(self.error(&format!("Expected `,` or `{}`", close as char))),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("Expected a string"));
+        }
+        let start = self.pos;
+        self.pos += 1;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                b'\\' => self.pos += 1,
+                b'"' => {
+                    return serde_json::from_str(&self.text[start..self.pos]).map_err(|_| {
+                        self.pos = start;
+                        self.error("Invalid string")
+                    });
+                }
+                _ => {}
+            }
+        }
+        self.pos = start;
+        Err(self.error("Unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::JsonDocument;
+
+    #[test]
+    fn test_edits_keep_formatting() {
+        let text = "{\n\t\"name\": \"a\",\n\t// comment\n\t\"version\": \"1.0.0\",\n\t\"scripts\": {},\n\t\"dependencies\": { \"b\": \"^1.0.0\" }\n}\n";
+        let mut doc = JsonDocument::parse(text).unwrap();
+        doc.set(&["version"], &json!("1.1.0"));
+        doc.set(&["scripts", "build"], &json!("tsc"));
+        doc.set(&["dependencies", "c"], &json!("^2.0.0"));
+        doc.set(&["devDependencies"], &json!({ "d": "1.0.0" }));
+        assert_eq!(
+            doc.as_str(),
+            "{\n\t\"name\": \"a\",\n\t// comment\n\t\"version\": \"1.1.0\",\n\t\"scripts\": {\n\t\t\"build\": \"tsc\"\n\t},\n\t\"dependencies\": { \"b\": \"^1.0.0\", \"c\": \"^2.0.0\" },\n\t\"devDependencies\": {\n\t\t\"d\": \"1.0.0\"\n\t}\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_inline_values() {
+        let mut doc = JsonDocument::parse("{ \"paths\": { \"a\": [\"a/src\"] }, \"references\": [] }").unwrap();
+        doc.set(&["paths", "a/*"], &json!(["a/src/*", "b"]));
+        doc.set(&["references"], &json!([{ "path": "../a" }, { "path": "../b" }]));
+        assert_eq!(
+            doc.as_str(),
+            "{ \"paths\": { \"a\": [\"a/src\"], \"a/*\": [\"a/src/*\", \"b\"] }, \"references\": [{ \"path\": \"../a\" }, { \"path\": \"../b\" }] }"
+        );
+        let mut doc = JsonDocument::parse("{\"a\": {\"b\": 1}}").unwrap();
+        doc.set(&["a", "c"], &json!({ "d": [1, 2] }));
+        assert_eq!(doc.as_str(), "{\"a\": {\"b\": 1, \"c\": {\"d\": [1, 2]}}}");
+    }
+
+    #[test]
+    fn test_update_applies_difference() {
+        let text = "{\n  \"z\": 1,\n  \"a\": { \"x\": 1, \"y\": 2 },\n  \"m\": [1, 2],\n  \"b\": true\n}";
+        let old: serde_json::Value = serde_json::from_str(text).unwrap();
+        let new = json!({ "z": 1, "a": { "y": 3 }, "m": [1, 2] });
+        let mut doc = JsonDocument::parse(text).unwrap();
+        doc.update(&old, &new);
+        assert_eq!(doc.as_str(), "{\n  \"z\": 1,\n  \"a\": { \"y\": 3 },\n  \"m\": [1, 2]\n}");
+        let mut doc = JsonDocument::parse("{\"a\": 1}").unwrap();
+        assert!(doc.remove(&["a"]));
+        assert_eq!(doc.as_str(), "{}");
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let err = JsonDocument::parse("{\n  \"a\": 1\n  \"b\": 2\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 3));
+    }
+}
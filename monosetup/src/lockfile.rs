@@ -0,0 +1,469 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    fs,
+    path::Path,
+};
+
+use serde_json::Value;
+
+use crate::mono::Version;
+
+const YARN_LOCK_HEADER: &str =
+    "# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.\n# yarn lockfile v1\n";
+
+/// One resolved package of a lockfile together with every range that resolves to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockEntry {
+    pub name: String,
+    pub ranges: BTreeSet<String>,
+    pub version: String,
+    pub resolved: Option<String>,
+    pub integrity: Option<String>,
+    pub dependencies: BTreeMap<String, String>,
+    pub optional_dependencies: BTreeMap<String, String>,
+}
+
+impl LockEntry {
+    fn key(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|r| maybe_wrap(&format!("{}@{}", self.name, r)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The package manager independent content of a `yarn.lock` or `package-lock.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    entries: Vec<LockEntry>,
+}
+
+impl Lockfile {
+    /// Loads the lockfile of a package directory, `yarn.lock` is preferred over `package-lock.json`.
+    /// A yarn berry (v2+) lockfile is skipped with a notice, the declared ranges are used instead.
+    pub fn load(dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let yarn = dir.join("yarn.lock");
+        if yarn.is_file() {
+            let content = fs::read_to_string(&yarn)?;
+            if is_berry(&content) {
+                println!("Skipping {}: yarn berry lockfiles are not supported", yarn.display());
+                return Ok(None);
+            }
+            return Ok(Some(Self::parse_yarn(&content)?));
+        }
+        let npm = dir.join("package-lock.json");
+        if npm.is_file() {
+            return Ok(Some(Self::parse_npm(&fs::read_to_string(npm)?)?));
+        }
+        Ok(None)
+    }
+
+    /// Parses a yarn v1 lockfile.
+    pub fn parse_yarn(content: &str) -> Result<Self, String> {
+        enum Section {
+            None,
+            Dependencies,
+            OptionalDependencies,
+            Other,
+        }
+        let mut entries = Vec::new();
+        let mut current: Option<LockEntry> = None;
+        let mut section = Section::None;
+        for (nr, line) in content.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - line.trim_start().len();
+            let line = line.trim();
+            match indent {
+                0 => {
+                    entries.extend(current.take());
+                    section = Section::None;
+                    let Some(specs) = line.strip_suffix(':') else {
+                        return Err(format!("yarn.lock:{}: expected an entry header", nr + 1));
+                    };
+                    let mut entry = LockEntry::default();
+                    for spec in specs.split(',') {
+                        let spec = unquote(spec.trim());
+                        let (name, range) = split_spec(&spec).ok_or_else(|| {
+                            format!("yarn.lock:{}: invalid specifier {spec}", nr + 1)
+                        })?;
+                        entry.name = name.into();
+                        entry.ranges.insert(range.into());
+                    }
+                    current = Some(entry);
+                }
+                2 => {
+                    let Some(entry) = current.as_mut() else {
+                        return Err(format!("yarn.lock:{}: field outside of an entry", nr + 1));
+                    };
+                    if let Some(s) = line.strip_suffix(':') {
+                        section = match s {
+                            "dependencies" => Section::Dependencies,
+                            "optionalDependencies" => Section::OptionalDependencies,
+                            _ => Section::Other,
+                        };
+                        continue;
+                    }
+                    section = Section::None;
+                    let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                    let value = unquote(value.trim());
+                    match key {
+                        "version" => entry.version = value,
+                        "resolved" => entry.resolved = Some(value),
+                        "integrity" => entry.integrity = Some(value),
+                        _ => {}
+                    }
+                }
+                _ => {
+                    let Some(entry) = current.as_mut() else {
+                        return Err(format!("yarn.lock:{}: field outside of an entry", nr + 1));
+                    };
+                    let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                    let (key, value) = (unquote(key), unquote(value.trim()));
+                    match section {
+                        Section::Dependencies => {
+                            entry.dependencies.insert(key, value);
+                        }
+                        Section::OptionalDependencies => {
+                            entry.optional_dependencies.insert(key, value);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        entries.extend(current);
+        Ok(Self { entries })
+    }
+
+    /// Parses a npm `package-lock.json` of lockfileVersion 2 or 3.
+    ///
+    /// npm keys its entries by install location, the ranges are recovered by resolving the
+    /// dependencies of every entry the same way node does.
+    pub fn parse_npm(content: &str) -> Result<Self, Box<dyn Error>> {
+        let v: Value = serde_json::from_str(content)?;
+        let lock_version = v
+            .get("lockfileVersion")
+            .and_then(Value::as_u64)
+            .unwrap_or(1);
+        if lock_version < 2 {
+            return Err(format!(
+                "package-lock.json lockfileVersion {lock_version} is not supported, only v2 and v3 are"
+            )
+            .into());
+        }
+        let packages = v
+            .get("packages")
+            .and_then(Value::as_object)
+            .ok_or("package-lock.json has no packages section")?;
+        let mut entries: Vec<LockEntry> = Vec::new();
+        let mut by_path: HashMap<&str, usize> = HashMap::new();
+        for (path, package) in packages {
+            let Some(i) = path.rfind("node_modules/") else {
+                continue;
+            };
+            if package.get("link").and_then(Value::as_bool) == Some(true) {
+                continue;
+            }
+            let Some(version) = package.get("version").and_then(Value::as_str) else {
+                continue;
+            };
+            let name = package
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or(&path[i + "node_modules/".len()..]);
+            let index = match entries
+                .iter()
+                .position(|e| e.name == name && e.version == version)
+            {
+                Some(index) => index,
+                None => {
+                    entries.push(LockEntry {
+                        name: name.into(),
+                        version: version.into(),
+                        resolved: string_field(package, "resolved"),
+                        integrity: string_field(package, "integrity"),
+                        dependencies: map_field(package, "dependencies"),
+                        optional_dependencies: map_field(package, "optionalDependencies"),
+                        ..Default::default()
+                    });
+                    entries.len() - 1
+                }
+            };
+            by_path.insert(path, index);
+        }
+        for (path, package) in packages {
+            let mut fields = vec!["dependencies", "optionalDependencies"];
+            // devDependencies are only installed for the root and workspace packages
+            if !path.contains("node_modules/") {
+                fields.push("devDependencies");
+            }
+            for field in fields {
+                for (dep, range) in map_field(package, field) {
+                    if let Some(index) = resolve_npm_path(&by_path, path, &dep) {
+                        entries[index].ranges.insert(range);
+                    }
+                }
+            }
+        }
+        entries.retain(|e| !e.ranges.is_empty());
+        Ok(Self { entries })
+    }
+
+    /// The version `name@range` is locked to.
+    pub fn locked_version(&self, name: &str, range: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|e| e.name == name && e.ranges.contains(range))
+            .map(|e| e.version.as_str())
+    }
+
+    /// Adds `name@range` to the entry locking `name` to `version`, returns false if there is none.
+    pub fn add_range(&mut self, name: &str, version: &str, range: &str) -> bool {
+        if self.locked_version(name, range).is_some() {
+            return true;
+        }
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.name == name && e.version == version)
+        {
+            Some(entry) => {
+                entry.ranges.insert(range.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every entry of the package `name`.
+    pub fn remove_package(&mut self, name: &str) {
+        self.entries.retain(|e| e.name != name);
+    }
+
+    /// Merges `other` into this lockfile, a range locked differently by both keeps the higher version.
+    pub fn merge(&mut self, other: Lockfile) {
+        for mut entry in other.entries {
+            for existing in self
+                .entries
+                .iter_mut()
+                .filter(|e| e.name == entry.name && e.version != entry.version)
+            {
+                let existing_is_higher =
+                    compare_versions(&existing.version, &entry.version).is_gt();
+                let shared: Vec<String> = existing
+                    .ranges
+                    .intersection(&entry.ranges)
+                    .cloned()
+                    .collect();
+                for range in shared {
+                    if existing_is_higher {
+                        entry.ranges.remove(&range);
+                    } else {
+                        existing.ranges.remove(&range);
+                    }
+                }
+            }
+            match self
+                .entries
+                .iter_mut()
+                .find(|e| e.name == entry.name && e.version == entry.version)
+            {
+                Some(existing) => existing.ranges.append(&mut entry.ranges),
+                None => self.entries.push(entry),
+            }
+        }
+        self.entries.retain(|e| !e.ranges.is_empty());
+    }
+
+    /// Serializes the lockfile in the yarn v1 format.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn to_yarn_string(&self) -> String {
+        let mut entries: Vec<(String, &LockEntry)> =
+            self.entries.iter().map(|e| (e.key(), e)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut out = String::from(YARN_LOCK_HEADER);
+        for (key, entry) in entries {
+            out.push_str(&format!(
+                "\n\n{key}:\n  version {}",
+                maybe_wrap(&entry.version)
+            ));
+            if let Some(resolved) = &entry.resolved {
+                out.push_str(&format!("\n  resolved {}", maybe_wrap(resolved)));
+            }
+            if let Some(integrity) = &entry.integrity {
+                out.push_str(&format!("\n  integrity {}", maybe_wrap(integrity)));
+            }
+            for (section, deps) in [
+                ("dependencies", &entry.dependencies),
+                ("optionalDependencies", &entry.optional_dependencies),
+            ] {
+                if deps.is_empty() {
+                    continue;
+                }
+                out.push_str(&format!("\n  {section}:"));
+                for (dep, range) in deps {
+                    out.push_str(&format!("\n    {} {}", maybe_wrap(dep), maybe_wrap(range)));
+                }
+            }
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// Checks if a `yarn.lock` is written by yarn berry, which starts it with a `__metadata` entry.
+fn is_berry(content: &str) -> bool {
+    content.lines().any(|line| line.trim_end() == "__metadata:")
+}
+
+fn resolve_npm_path(by_path: &HashMap<&str, usize>, from: &str, dep: &str) -> Option<usize> {
+    let mut base = from;
+    loop {
+        let candidate = if base.is_empty() {
+            format!("node_modules/{dep}")
+        } else {
+            format!("{base}/node_modules/{dep}")
+        };
+        if let Some(index) = by_path.get(candidate.as_str()) {
+            return Some(*index);
+        }
+        if base.is_empty() {
+            return None;
+        }
+        base = base
+            .rfind("node_modules/")
+            .map_or("", |i| base[..i].trim_end_matches('/'));
+    }
+}
+
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    match (Version::try_from(a), Version::try_from(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// Splits `name@range` in its parts, scoped names start with an `@` themselves.
+pub fn split_spec(spec: &str) -> Option<(&str, &str)> {
+    let at = spec.get(1..)?.find('@')? + 1;
+    Some((&spec[..at], &spec[at + 1..]))
+}
+
+fn string_field(v: &Value, field: &str) -> Option<String> {
+    v.get(field).and_then(Value::as_str).map(Into::into)
+}
+
+fn map_field(v: &Value, field: &str) -> BTreeMap<String, String> {
+    v.get(field)
+        .and_then(Value::as_object)
+        .map(|m| {
+            m.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn unquote(s: &str) -> String {
+    if s.starts_with('"') {
+        serde_json::from_str(s).unwrap_or_else(|_| s.trim_matches('"').into())
+    } else {
+        s.into()
+    }
+}
+
+/// Quotes a key or value the way yarn does when writing its lockfile.
+fn maybe_wrap(s: &str) -> String {
+    let needs_quotes = s == "true"
+        || s == "false"
+        || !s.starts_with(|c: char| c.is_ascii_alphabetic())
+        || s.contains(|c: char| {
+            c == ':' || c.is_whitespace() || matches!(c, '\\' | '"' | ',' | '[' | ']')
+        });
+    if needs_quotes {
+        serde_json::to_string(s).unwrap()
+    } else {
+        s.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lockfile;
+
+    const YARN_LOCK: &str = r#"# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+# yarn lockfile v1
+
+
+"@babel/code-frame@^7.0.0", "@babel/code-frame@^7.10.4":
+  version "7.12.13"
+  resolved "https://registry.yarnpkg.com/@babel/code-frame/-/code-frame-7.12.13.tgz#dcfc826beef65e75c50e21d3837d7d95798dd658"
+  integrity sha512-HV1Cm0Q3ZrpCR93tkWOYiuYIgLxZXZFVG2VgK+MBWjUqZTundupbfx2aXarXuw5Ko5aMcjtJgbSs4vUGBS5v6g==
+  dependencies:
+    "@babel/highlight" "^7.12.13"
+
+lodash@^4.17.21:
+  version "4.17.21"
+  resolved "https://registry.yarnpkg.com/lodash/-/lodash-4.17.21.tgz#679591c564c3bffaae8454cf0b3df370c3d6911c"
+  integrity sha512-v2kDEe57lecTulaDIuNTPy3Ry4gLGJ6Z1O3vE1krgXZNrsQ+LFTGHVxVjcXPs17LhbZVGedAJv8XZ1tvj5FvSg==
+"#;
+
+    #[test]
+    fn test_yarn_roundtrip() {
+        let lock = Lockfile::parse_yarn(YARN_LOCK).unwrap();
+        assert_eq!(
+            lock.locked_version("@babel/code-frame", "^7.10.4"),
+            Some("7.12.13")
+        );
+        assert_eq!(lock.locked_version("lodash", "^4.17.21"), Some("4.17.21"));
+        assert_eq!(lock.to_yarn_string(), YARN_LOCK);
+    }
+
+    #[test]
+    fn test_npm_lock() {
+        let lock = Lockfile::parse_npm(
+            r#"{
+            "lockfileVersion": 3,
+            "packages": {
+                "": { "name": "pkg", "dependencies": { "a": "^1.0.0" }, "devDependencies": { "b": "~2.0.0" } },
+                "node_modules/a": { "version": "1.2.0", "dependencies": { "b": "^1.0.0" } },
+                "node_modules/a/node_modules/b": { "version": "1.5.0" },
+                "node_modules/b": { "version": "2.0.3", "dev": true }
+            }
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(lock.locked_version("a", "^1.0.0"), Some("1.2.0"));
+        assert_eq!(lock.locked_version("b", "^1.0.0"), Some("1.5.0"));
+        assert_eq!(lock.locked_version("b", "~2.0.0"), Some("2.0.3"));
+    }
+
+    #[test]
+    fn test_berry_lock_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("monosetup-lockfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let berry = "# This file is generated by running \"yarn install\" inside your project.\n\n__metadata:\n  version: 6\n  cacheKey: 8\n\n\"lodash@npm:^4.17.21\":\n  version: 4.17.21\n  resolution: \"lodash@npm:4.17.21\"\n";
+        std::fs::write(dir.join("yarn.lock"), berry).unwrap();
+        assert_eq!(Lockfile::load(&dir).unwrap(), None);
+        std::fs::write(dir.join("yarn.lock"), YARN_LOCK).unwrap();
+        assert!(Lockfile::load(&dir).unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_keeps_higher_version() {
+        let mut a = Lockfile::parse_yarn("lodash@^4.17.0:\n  version \"4.17.20\"\n").unwrap();
+        let b = Lockfile::parse_yarn("lodash@^4.17.0, lodash@^4.17.21:\n  version \"4.17.21\"\n")
+            .unwrap();
+        a.merge(b);
+        assert_eq!(a.locked_version("lodash", "^4.17.0"), Some("4.17.21"));
+        assert_eq!(a.entries.len(), 1);
+    }
+}
@@ -1,15 +1,42 @@
+mod affected;
+mod bundler;
+mod cache;
+mod config;
+mod diagnostics;
+mod graph;
+mod json_edit;
+mod lockfile;
+mod manifest;
 mod mono;
+mod node_modules;
+mod nohoist;
+mod originals;
+mod runner;
+mod scripts;
+mod tsconfig;
+mod watch;
+mod workspace;
 use std::{
+    collections::BTreeSet,
     error::Error,
+    path::Path,
     process::{Command, Stdio},
-    sync::OnceLock,
+    sync::{atomic::AtomicBool, OnceLock},
     thread::spawn,
 };
 
-use clap::{Parser, Subcommand};
-use mono::setup_mono;
+use cache::{package_hashes, BuildCache};
+use clap::{Args, Parser, Subcommand};
+use config::{Config, CONFIG_FILE};
+use diagnostics::Diagnostic;
+use graph::{GraphFormat, PackageGraph};
+use mono::{setup_mono, MergeOptions};
+use runner::Cached;
+use workspace::Workspace;
 
-static MERGE_DEPS: OnceLock<bool> = OnceLock::new();
+static MERGE_DEPS: OnceLock<MergeOptions> = OnceLock::new();
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 static REPOS: [(&str, &str); 5] = [
     (
@@ -37,29 +64,200 @@ struct Cli {
 enum Commands {
     /// Initialize the monorepo by cloning all repos
     Init {
-        #[arg(short, long)]
-        merge: bool,
+        #[command(flatten)]
+        merge: MergeArgs,
+    },
+    /// Sync the packages already in the monorepo, can be re-run at any time
+    Setup {
+        #[command(flatten)]
+        merge: MergeArgs,
     },
     /// Clean project
     Clean,
+    /// Restore every file monosetup rewrote, leaving the packages as standalone projects
+    Eject,
+    /// Build the packages with their build:notest script in dependency order
+    Build {
+        #[command(flatten)]
+        selection: Selection,
+        /// Number of packages built at the same time
+        #[arg(short, long, default_value_t = default_jobs())]
+        jobs: usize,
+        /// Also build packages whose sources did not change since their last build
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Rebuild changed packages and their dependents whenever their sources change
+    Watch {
+        /// Number of packages built at the same time
+        #[arg(short, long, default_value_t = default_jobs())]
+        jobs: usize,
+    },
+    /// Run a script in the packages that define it
+    Run {
+        script: String,
+        #[command(flatten)]
+        selection: Selection,
+        /// Run all selected packages at once instead of after the packages they depend on
+        #[arg(long, conflicts_with = "topological")]
+        parallel: bool,
+        /// Run each package after the packages it depends on (default)
+        #[arg(long)]
+        topological: bool,
+        /// Number of packages run at the same time
+        #[arg(short, long, default_value_t = default_jobs())]
+        jobs: usize,
+    },
+    /// Print the packages affected by the changes since a git ref, one per line
+    Affected {
+        /// The git ref to compare with, e.g. `origin/main`
+        #[arg(long)]
+        since: String,
+    },
+    /// Print the dependencies between the packages and the order they are built in
+    Graph {
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+}
+
+#[derive(Debug, Args)]
+struct MergeArgs {
+    #[arg(short, long)]
+    merge: bool,
+    /// Allow merging dependencies across major versions
+    #[arg(long, requires = "merge")]
+    allow_major: bool,
+    /// Only merge these dependencies
+    #[arg(long, value_delimiter = ',', requires = "merge")]
+    include: Vec<String>,
+    /// Never merge these dependencies
+    #[arg(long, value_delimiter = ',', requires = "merge")]
+    exclude: Vec<String>,
+}
+
+/// Selects packages by the internal dependency graph.
+#[derive(Debug, Args)]
+struct Selection {
+    /// Only packages whose name or directory matches, accepts globs such as `@nmshd/*`
+    #[arg(short = 'F', long)]
+    filter: Vec<String>,
+    /// Also select the packages the selected ones depend on
+    #[arg(long)]
+    with_deps: bool,
+    /// Also select the packages depending on the selected ones
+    #[arg(long)]
+    with_dependents: bool,
+    /// Only packages affected by the changes since this git ref, see `affected`
+    #[arg(long)]
+    since: Option<String>,
+}
+
+impl Selection {
+    fn select(&self, workspace: &Workspace, graph: &PackageGraph) -> Result<BTreeSet<usize>, Box<dyn Error>> {
+        let mut selected = graph.filter(&workspace.members(), &self.filter)?;
+        if let Some(since) = &self.since {
+            let affected = affected::affected(workspace, graph, since)?;
+            selected.retain(|i| affected.contains(i));
+        }
+        if self.with_deps {
+            selected = graph.with_dependencies(&selected);
+        }
+        if self.with_dependents {
+            selected = graph.with_dependents(&selected);
+        }
+        Ok(selected)
+    }
+}
+
+impl From<&MergeArgs> for MergeOptions {
+    fn from(args: &MergeArgs) -> Self {
+        MergeOptions {
+            enabled: args.merge,
+            allow_major: args.allow_major,
+            include: args.include.clone(),
+            exclude: args.exclude.clone(),
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
+    match Config::load(&Path::new("../").join(CONFIG_FILE)) {
+        Ok(config) => CONFIG.set(config).unwrap(),
+        Err(e) => report(e),
+    }
 
     match &cli.command {
         Commands::Init { merge } => {
-            MERGE_DEPS.set(*merge).unwrap();
+            MERGE_DEPS.set(merge.into()).unwrap();
             if let Err(e) = initialize() {
-                println!("{e}");
+                report(e);
+            }
+        }
+        Commands::Setup { merge } => {
+            MERGE_DEPS.set(merge.into()).unwrap();
+            if let Err(e) = setup_mono() {
+                report(e);
             }
         }
         Commands::Clean => {
             if let Err(e) = clean() {
-                println!("{e}");
+                report(e);
+            }
+        }
+        Commands::Eject => {
+            if let Err(e) = eject() {
+                report(e);
+            }
+        }
+        Commands::Build {
+            selection,
+            jobs,
+            force,
+        } => {
+            if let Err(e) = build(selection, *jobs, *force) {
+                report(e);
             }
         }
+        Commands::Watch { jobs } => {
+            if let Err(e) = watch(*jobs) {
+                report(e);
+            }
+        }
+        Commands::Run {
+            script,
+            selection,
+            parallel,
+            topological,
+            jobs,
+        } => {
+            // Topological unless --parallel is given, the two flags conflict
+            let topological = *topological || !*parallel;
+            if let Err(e) = run(script, selection, topological, *jobs) {
+                report(e);
+            }
+        }
+        Commands::Affected { since } => {
+            if let Err(e) = print_affected(since) {
+                report(e);
+            }
+        }
+        Commands::Graph { format } => {
+            if let Err(e) = graph(*format) {
+                report(e);
+            }
+        }
+    }
+}
+
+/// Prints an error, diagnostics are rendered with the offending source line, and exits.
+fn report(e: Box<dyn Error>) -> ! {
+    match e.downcast_ref::<Diagnostic>() {
+        Some(diagnostic) => eprintln!("{diagnostic}"),
+        None => eprintln!("error: {e}"),
     }
+    std::process::exit(1)
 }
 
 fn initialize() -> Result<(), Box<dyn Error>> {
@@ -74,6 +272,7 @@ fn initialize() -> Result<(), Box<dyn Error>> {
     for handle in handles {
         handle.join().unwrap();
     }
+    println!("All repos cloned: Initializing monorepo");
     setup_mono()?;
     Ok(())
 }
@@ -117,7 +316,85 @@ fn init_repo((dir, url): (&'static str, &'static str)) -> Result<(), Box<dyn Err
     Ok(())
 }
 
+fn eject() -> Result<(), Box<dyn Error>> {
+    let root = std::fs::canonicalize("../")?;
+    let (restored, removed) = originals::eject(&root)?;
+    for path in restored {
+        println!("Restored {}", path.strip_prefix(&root)?.display());
+    }
+    for path in removed {
+        println!("Removed {}", path.strip_prefix(&root)?.display());
+    }
+    println!("Ejected: the packages are standalone projects again");
+    Ok(())
+}
+
+fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn build(selection: &Selection, jobs: usize, force: bool) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::current()?;
+    let graph = PackageGraph::new(&workspace);
+    let config = &CONFIG.get().unwrap().build;
+    let cached = Cached {
+        cache: &BuildCache::load(&workspace.root, config)?,
+        hashes: package_hashes(&workspace, &graph, &config.outputs)?,
+        force,
+    };
+    let selected = selection.select(&workspace, &graph)?;
+    let cancel = AtomicBool::new(false);
+    runner::run_script(
+        &workspace,
+        &graph,
+        &selected,
+        "build:notest",
+        jobs,
+        Some(&cached),
+        &cancel,
+    )
+}
+
+fn watch(jobs: usize) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::current()?;
+    let graph = PackageGraph::new(&workspace);
+    graph.topological_order()?;
+    watch::watch(&workspace, &graph, &CONFIG.get().unwrap().build.outputs, jobs)
+}
+
+fn run(script: &str, selection: &Selection, topological: bool, jobs: usize) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::current()?;
+    let mut graph = PackageGraph::new(&workspace);
+    let selected = selection.select(&workspace, &graph)?;
+    if !topological {
+        graph = graph.unordered();
+    }
+    let cancel = AtomicBool::new(false);
+    runner::run_script(&workspace, &graph, &selected, script, jobs, None, &cancel)
+}
+
+fn print_affected(since: &str) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::current()?;
+    let graph = PackageGraph::new(&workspace);
+    for i in affected::affected(&workspace, &graph, since)? {
+        println!("{}", graph.names[i]);
+    }
+    Ok(())
+}
+
+fn graph(format: GraphFormat) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::current()?;
+    print!("{}", PackageGraph::new(&workspace).render(format)?);
+    Ok(())
+}
+
 fn clean() -> Result<(), Box<dyn Error>> {
+    let root = std::fs::canonicalize("../")?;
+    // A generated solution tsconfig.json would reference the removed packages
+    let created = originals::load_created(&root)?;
+    if created.iter().any(|p| p == Path::new("tsconfig.json")) && root.join("tsconfig.json").is_file() {
+        std::fs::remove_file(root.join("tsconfig.json"))?;
+    }
     if cfg!(target_os = "windows") {
         let current_dir = std::fs::canonicalize("../")?;
         Command::new("cmd")
@@ -125,25 +402,36 @@ fn clean() -> Result<(), Box<dyn Error>> {
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("cmd")
             .args(["/C", "rm -rf node_modules"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("cmd")
             .args(["/C", "rm -rf package.json"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("cmd")
             .args(["/C", "rm -rf yarn.lock"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
+        Command::new("cmd")
+            .args(["/C", "rm -rf .monosetup"])
+            .current_dir(&current_dir)
+            .stdout(Stdio::inherit())
+            .spawn()
+            .expect("Failed to run clean")
+            .wait()?;
     } else {
         let current_dir = std::fs::canonicalize("../")?;
         Command::new("sh")
@@ -151,25 +439,36 @@ fn clean() -> Result<(), Box<dyn Error>> {
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("sh")
             .args(["-c", "rm -rf node_modules"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("sh")
             .args(["-c", "rm -rf package.json"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
         Command::new("sh")
             .args(["-c", "rm -rf yarn.lock"])
             .current_dir(&current_dir)
             .stdout(Stdio::inherit())
             .spawn()
-            .expect("Failed to run clean");
+            .expect("Failed to run clean")
+            .wait()?;
+        Command::new("sh")
+            .args(["-c", "rm -rf .monosetup"])
+            .current_dir(&current_dir)
+            .stdout(Stdio::inherit())
+            .spawn()
+            .expect("Failed to run clean")
+            .wait()?;
     };
     Ok(())
 }
@@ -0,0 +1,180 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{diagnostics::Diagnostic, json_edit::JsonDocument, mono::DependencyKind};
+
+/// Dependency names mapped to their version range, in the order of the package.json.
+pub type DependencyMap = IndexMap<String, String>;
+
+/// The fields of a package.json monosetup works with, every other field is kept in `extra`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageManifest {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scripts: Option<IndexMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<DependencyMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_dependencies: Option<DependencyMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_dependencies: Option<DependencyMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub optional_dependencies: Option<DependencyMap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolutions: Option<IndexMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overrides: Option<Value>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+impl PackageManifest {
+    pub fn dependencies(&self, kind: DependencyKind) -> Option<&DependencyMap> {
+        match kind {
+            DependencyKind::Dependencies => self.dependencies.as_ref(),
+            DependencyKind::DevDependencies => self.dev_dependencies.as_ref(),
+            DependencyKind::PeerDependencies => self.peer_dependencies.as_ref(),
+            DependencyKind::OptionalDependencies => self.optional_dependencies.as_ref(),
+        }
+    }
+
+    pub fn dependencies_mut(&mut self, kind: DependencyKind) -> Option<&mut DependencyMap> {
+        match kind {
+            DependencyKind::Dependencies => self.dependencies.as_mut(),
+            DependencyKind::DevDependencies => self.dev_dependencies.as_mut(),
+            DependencyKind::PeerDependencies => self.peer_dependencies.as_mut(),
+            DependencyKind::OptionalDependencies => self.optional_dependencies.as_mut(),
+        }
+    }
+}
+
+/// A package.json loaded from disk, `render` produces the new content after changes to
+/// `manifest`, keeping the formatting of unchanged values.
+#[derive(Debug, Clone)]
+pub struct ManifestFile {
+    pub path: PathBuf,
+    pub manifest: PackageManifest,
+    content: String,
+    original: Value,
+}
+
+impl ManifestFile {
+    pub fn load(path: &Path) -> Result<Self, Diagnostic> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| Diagnostic::new(format!("Failed to read package.json: {e}")).in_file(path))?;
+        let original: Value = serde_json::from_str(&content).map_err(|e| {
+            Diagnostic::new("Failed to parse package.json").at_line(path, &content, e.line(), e.column(), e.to_string())
+        })?;
+        if let Err((field, message)) = validate(&original) {
+            return Err(locate(path, &content, &field, message));
+        }
+        let manifest = serde_json::from_value(original.clone())
+            .map_err(|e| Diagnostic::new(format!("Invalid package.json: {e}")).in_file(path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            manifest,
+            content,
+            original,
+        })
+    }
+
+    /// A diagnostic pointing at the value of `field` in the package.json as it was loaded.
+    pub fn error_at(&self, field: &[&str], message: impl Into<String>) -> Diagnostic {
+        locate(&self.path, &self.content, field, message)
+    }
+
+    /// The content of the file as it was loaded.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The new content of the file if the manifest changed, only the changed values are rewritten.
+    pub fn render(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let new = serde_json::to_value(&self.manifest)?;
+        if new == self.original {
+            return Ok(None);
+        }
+        let mut document = JsonDocument::parse(self.content.as_str())?;
+        document.update(&self.original, &new);
+        Ok(Some(document.as_str().to_string()))
+    }
+}
+
+fn locate(path: &Path, content: &str, field: &[impl AsRef<str>], message: impl Into<String>) -> Diagnostic {
+    let label = field.iter().map(AsRef::as_ref).collect::<Vec<_>>().join(".");
+    match JsonDocument::parse(content) {
+        Ok(document) => Diagnostic::new(message).at(path, content, document.span(field), label),
+        Err(_) => Diagnostic::new(message).in_file(path),
+    }
+}
+
+/// Checks the fields of the typed model, returns the offending field and what is wrong with it.
+fn validate(v: &Value) -> Result<(), (Vec<String>, String)> {
+    let Some(v) = v.as_object() else {
+        return Err((vec![], "package.json must be an object".into()));
+    };
+    for field in ["name", "version"] {
+        match v.get(field) {
+            Some(Value::String(s)) if !s.is_empty() => {}
+            Some(_) => return Err((vec![field.into()], format!("`{field}` must be a non empty string"))),
+            None => return Err((vec![field.into()], format!("`{field}` is missing"))),
+        }
+    }
+    let maps = DependencyKind::ALL
+        .iter()
+        .map(|k| k.field())
+        .chain(["scripts", "resolutions"]);
+    for field in maps {
+        match v.get(field) {
+            None => {}
+            Some(Value::Object(map)) => {
+                if let Some((key, _)) = map.iter().find(|(_, v)| !v.is_string()) {
+                    return Err((vec![field.into(), key.clone()], format!("`{field}.{key}` must be a string")));
+                }
+            }
+            Some(_) => return Err((vec![field.into()], format!("`{field}` must be an object"))),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::ManifestFile;
+
+    #[test]
+    fn test_validation_names_field() {
+        let dir = std::env::temp_dir().join(format!("monosetup-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("package.json");
+        std::fs::write(&path, r#"{ "name": "a", "version": "1.0.0", "devDependencies": { "b": 1 } }"#).unwrap();
+        let err = ManifestFile::load(&path).unwrap_err();
+        assert_eq!(err.message, "`devDependencies.b` must be a string");
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (1, 62));
+        assert_eq!(location.label.as_deref(), Some("devDependencies.b"));
+        std::fs::write(&path, r#"{ "name": "a", "private": true, "dependencies": { "b": "^1.0.0" } }"#).unwrap();
+        let err = ManifestFile::load(&path).unwrap_err();
+        assert_eq!(err.message, "`version` is missing");
+        std::fs::write(&path, "{\n  \"name\": \"a\",\n  \"version\": \"1.0.0\",\n  \"private\": true\n}\n").unwrap();
+        let mut file = ManifestFile::load(&path).unwrap();
+        assert_eq!(file.render().unwrap(), None);
+        file.manifest.version = "1.1.0".into();
+        assert_eq!(
+            file.render().unwrap().as_deref(),
+            Some("{\n  \"name\": \"a\",\n  \"version\": \"1.1.0\",\n  \"private\": true\n}\n")
+        );
+        std::fs::remove_dir_all(Path::new(&dir)).unwrap();
+    }
+}
@@ -1,13 +1,97 @@
-use std::{cmp::Ordering, error::Error, fs, collections::{HashMap, VecDeque}};
-use serde::{Serialize, Deserialize};
+use std::{
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    error::Error,
+    fs,
+};
+
 use serde_json::Value;
-use crate::MERGE_DEPS;
+
+use crate::{
+    bundler::update_bundler_configs,
+    config::{Config, HoistConfig, NotestConfig},
+    diagnostics::Diagnostic,
+    json_edit::JsonDocument,
+    lockfile::{split_spec, Lockfile},
+    manifest::{DependencyMap, ManifestFile, PackageManifest},
+    node_modules::{update_node_modules_paths, Hoisting},
+    nohoist::{conflicting_typings, incompatible_versions, nohoist_patterns},
+    originals::Generated,
+    scripts::{notest_variants, TestRules},
+    tsconfig::update_tsconfigs,
+    workspace::Workspace,
+    CONFIG, MERGE_DEPS,
+};
+
+/// How `--merge` unifies the runtime dependencies of the packages.
+#[derive(Debug, Default, Clone)]
+pub struct MergeOptions {
+    pub enabled: bool,
+    /// Also raise dependencies to a higher major version
+    pub allow_major: bool,
+    /// If not empty only these dependencies are merged
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl MergeOptions {
+    fn applies_to(&self, dep: &str) -> bool {
+        self.enabled
+            && (self.include.is_empty() || self.include.iter().any(|d| d == dep))
+            && !self.exclude.iter().any(|d| d == dep)
+    }
+}
+
+/// The kind of a version change, changes crossing a compatibility boundary are major.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ChangeKind {
+    Patch,
+    Minor,
+    Major,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeKind::Patch => write!(f, "patch"),
+            ChangeKind::Minor => write!(f, "minor"),
+            ChangeKind::Major => write!(f, "major"),
+        }
+    }
+}
+
+/// The dependency fields of a package.json the sync engine knows about.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DependencyKind {
+    Dependencies,
+    DevDependencies,
+    PeerDependencies,
+    OptionalDependencies,
+}
+
+impl DependencyKind {
+    pub const ALL: [DependencyKind; 4] = [
+        DependencyKind::Dependencies,
+        DependencyKind::DevDependencies,
+        DependencyKind::PeerDependencies,
+        DependencyKind::OptionalDependencies,
+    ];
+
+    pub fn field(&self) -> &'static str {
+        match self {
+            DependencyKind::Dependencies => "dependencies",
+            DependencyKind::DevDependencies => "devDependencies",
+            DependencyKind::PeerDependencies => "peerDependencies",
+            DependencyKind::OptionalDependencies => "optionalDependencies",
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Version {
-    pub major: Option<u8>,
-    pub minor: Option<u8>,
-    pub patch: Option<u8>,
+    pub major: Option<u64>,
+    pub minor: Option<u64>,
+    pub patch: Option<u64>,
     pub appendix: Option<String>,
     pub patch_strategy: PatchStrategy,
 }
@@ -25,7 +109,7 @@ impl From<Version> for String {
         let mut s = VecDeque::with_capacity(10);
         for mut v in [value.patch, value.minor, value.major].into_iter().flatten() {
             loop {
-                let c = char::from_digit(v as u32 % 10, 10).unwrap();
+                let c = char::from_digit((v % 10) as u32, 10).unwrap();
                 s.push_front(c);
                 v /= 10;
                 if v == 0 {
@@ -80,10 +164,10 @@ impl TryFrom<&str> for Version {
                 }
                 for (index, v) in v[1..].split('.').enumerate() {
                     match index {
-                        0 => major = Some(v.parse::<u8>().map_err(|err| err.to_string())?),
-                        1 => minor = Some(v.parse::<u8>().map_err(|err| err.to_string())?),
+                        0 => major = Some(v.parse::<u64>().map_err(|err| err.to_string())?),
+                        1 => minor = Some(v.parse::<u64>().map_err(|err| err.to_string())?),
                         2 => 
-                                    patch = Some(v.parse::<u8>().map_err(|err| err.to_string())?),
+                                    patch = Some(v.parse::<u64>().map_err(|err| err.to_string())?),
                         _ => 
                         return Err(format!("Failed to parse package version a version is of format x.x.x and {} was suplied", v))
                     }
@@ -111,20 +195,20 @@ impl TryFrom<&str> for Version {
                 let mut strategy = PatchStrategy::None;
                 for (index, v) in v.split('.').enumerate() {
                     match index {
-                        0 => major = Some(v.parse::<u8>().map_err(|err| err.to_string())?),
+                        0 => major = Some(v.parse::<u64>().map_err(|err| err.to_string())?),
                         1 => {
                             if v == "x" {
                                 strategy = PatchStrategy::Minor;
                                 break;
                             }
-                            minor = Some(v.parse::<u8>().map_err(|err| err.to_string())?);
+                            minor = Some(v.parse::<u64>().map_err(|err| err.to_string())?);
                         },
                         2 => {
                             if v == "x" {
                                 strategy = PatchStrategy::Patch;
                                 break;
                             }
-                            patch = Some(v.parse::<u8>().map_err(|err| err.to_string())?);
+                            patch = Some(v.parse::<u64>().map_err(|err| err.to_string())?);
                         }
                         _ => 
                         return Err(format!("Failed to parse package version a version is of format x.x.x and {} was suplied", v))
@@ -146,7 +230,7 @@ impl TryFrom<&str> for Version {
                 appendix,
                 patch_strategy: PatchStrategy::Major,
             }),
-            _ => Err("Failed parsion Version: the slice is empty".into()),
+            _ => Err(format!("Failed to parse version `{v}`, it has to start with a number, `~`, `^` or `*`")),
         }
     }
 }
@@ -187,39 +271,115 @@ impl Ord for Version {
     }
 }
 
+impl Version {
+    /// Checks if both versions are within the same compatibility range, `0.x` versions are only
+    /// compatible within the same minor version.
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        if self.patch_strategy == PatchStrategy::Major || other.patch_strategy == PatchStrategy::Major {
+            return self.patch_strategy == other.patch_strategy;
+        }
+        self.major == other.major && (self.major != Some(0) || self.minor == other.minor)
+    }
+
+    /// Classifies the change from self to `to`.
+    pub fn change_kind(&self, to: &Version) -> ChangeKind {
+        if !self.is_compatible(to) {
+            ChangeKind::Major
+        } else if self.minor != to.minor {
+            ChangeKind::Minor
+        } else {
+            ChangeKind::Patch
+        }
+    }
+
+    /// Checks if the concrete `version` lies within the range described by self, following the
+    /// npm semantics for `^`, `~` and `x` ranges.
+    pub fn matches(&self, version: &Version) -> bool {
+        let Some(major) = version.major else {
+            return false;
+        };
+        let v = (major, version.minor.unwrap_or(0), version.patch.unwrap_or(0));
+        let lower = (self.major.unwrap_or(0), self.minor.unwrap_or(0), self.patch.unwrap_or(0));
+        // Prereleases only match ranges that opt into prereleases of the same version
+        if version.appendix.is_some() && (self.appendix.is_none() || v != lower) {
+            return false;
+        }
+        match self.patch_strategy {
+            PatchStrategy::Major => true,
+            PatchStrategy::None => match (self.minor, self.patch) {
+                (None, _) => v.0 == lower.0,
+                (Some(_), None) => (v.0, v.1) == (lower.0, lower.1),
+                _ => v == lower && version.appendix == self.appendix,
+            },
+            PatchStrategy::Patch => {
+                v >= lower && v.0 == lower.0 && (self.minor.is_none() || v.1 == lower.1)
+            }
+            PatchStrategy::Minor => {
+                v >= lower
+                    && if lower.0 > 0 || self.minor.is_none() {
+                        v.0 == lower.0
+                    } else if lower.1 > 0 || self.patch.is_none() {
+                        (v.0, v.1) == (0, lower.1)
+                    } else {
+                        v == lower
+                    }
+            }
+        }
+    }
+}
+
+/// Syncs the packages of the monorepo. Running it again converges to the same result, files that
+/// are already up to date are left untouched.
 pub fn setup_mono() -> Result<(), Box<dyn Error>> {
-    println!("All repos cloned: Initializing monorepo");
+    setup_workspace(Workspace::current()?, CONFIG.get().unwrap())
+}
+
+fn setup_workspace(mut workspace: Workspace, config: &Config) -> Result<(), Box<dyn Error>> {
     let mut dependencies: HashMap<&str, (usize, Version)> = HashMap::new();
+    // Every declared version of the runtime dependencies, needed to merge within compatible ranges
+    let mut declared: HashMap<&str, Vec<Version>> = HashMap::new();
     let mut dev_dependencies: HashMap<&str, (usize, Version)> = HashMap::new();
-    let dir = fs::canonicalize("../packages")?;
-    let mut package_deps: Vec<serde_json::Value> = Vec::with_capacity(30);
-    let mut package_dev_deps: Vec<serde_json::Value> = Vec::with_capacity(30);
-    for package in fs::read_dir(dir)? {
-        let mut package = package?.path();
-        if !package.is_dir() {
-            continue;
+    let existing_root = fs::read_to_string(workspace.root.join("package.json")).ok();
+    let root_dev_deps: DependencyMap = existing_root
+        .as_deref()
+        .and_then(|root| serde_json::from_str::<Value>(root).ok())
+        .and_then(|root| serde_json::from_value(root["devDependencies"].clone()).ok())
+        .unwrap_or_default();
+    let mut generated = Generated::load(&workspace.root)?;
+    // Root devDependencies the last run did not generate were added by hand and stay in the root
+    let previous = generated.root_package.as_ref().and_then(|r| r.get("devDependencies")).and_then(Value::as_object);
+    let mut root_pinned = root_dev_deps.clone();
+    root_pinned.retain(|k, _| !previous.is_some_and(|p| p.contains_key(k)));
+    // devDependencies hoisted by an earlier run count as used by the packages declaring them upstream
+    restore_hoisted(&mut workspace, &root_dev_deps);
+    let mut package_deps: Vec<DependencyMap> = Vec::with_capacity(30);
+    let mut package_dev_deps: Vec<DependencyMap> = Vec::with_capacity(30);
+    let mut package_peer_deps: Vec<(String, DependencyMap)> = Vec::with_capacity(30);
+    // The packages of the workspace with their local version
+    let mut local_packages: HashMap<String, String> = HashMap::new();
+    // Resolutions only take effect in the workspace root, they are lifted there with their origin.
+    let mut resolutions: BTreeMap<String, (String, String)> = BTreeMap::new();
+    for package in workspace.packages.iter() {
+        let manifest = package.manifest.manifest.clone();
+        local_packages.insert(manifest.name.clone(), manifest.version.clone());
+        collect_resolutions(&mut resolutions, &manifest);
+        if let Some(peers) = manifest.peer_dependencies.clone() {
+            package_peer_deps.push((manifest.name.clone(), peers));
         }
-        package.push("package.json");
-        let mut v: serde_json::Value = serde_json::from_reader(fs::File::open(
-                package
-                )?)?;
         // Not all packages have dependencies
-        let mut deps = v.as_object_mut().unwrap().remove("dependencies").unwrap_or(Value::Object(serde_json::Map::new()));
-        // Add the package itself to the potential dependencies
-        deps.as_object_mut().unwrap().insert(v.as_object().unwrap().get("name").unwrap().to_string(), v.as_object().unwrap().get("version").unwrap().clone());
+        let mut deps = manifest.dependencies.unwrap_or_default();
+        // Optional dependencies are installed like dependencies and share their versions
+        deps.extend(manifest.optional_dependencies.unwrap_or_default());
         package_deps.push(deps);
-        let mut dev_deps = v.as_object_mut().unwrap().remove("devDependencies").unwrap();
-        // Add the package itself to the potential devDependencies
-        dev_deps.as_object_mut().unwrap().insert(v.as_object().unwrap().get("name").unwrap().to_string(), v.as_object().unwrap().get("version").unwrap().clone()); 
-        package_dev_deps.push(dev_deps);
-    }
-    for d in package_deps.iter() {
-        for (k, ver_a) in d.as_object().unwrap() {
-            let ver_a: Version = Version::try_from(ver_a.as_str().unwrap())?;
-            let mut k = k.as_str();
-            if k.contains('"') {
-                k = &k[1..=k.len() - 2];
-            }
+        package_dev_deps.push(manifest.dev_dependencies.unwrap_or_default());
+    }
+    // Internal dependencies may use the `workspace:` protocol, they are linked to the local version
+    let external = |(k, _): &(&String, &String)| !local_packages.contains_key(*k);
+    for (d, file) in package_deps.iter().zip(workspace.packages.iter().map(|p| &p.manifest)) {
+        for (k, ver_a) in d.iter().filter(external) {
+            let ver_a = parse_declared(file, k, ver_a)?;
+            let k = k.as_str();
+            declared.entry(k).or_default().push(ver_a.clone());
             dependencies.entry(k).and_modify(|(n, ver_b)| {
                 *n += 1;
                 if ver_a > *ver_b {
@@ -228,13 +388,10 @@ pub fn setup_mono() -> Result<(), Box<dyn Error>> {
             }).or_insert((1, ver_a));
         }
     }
-    for d in package_dev_deps.iter() {
-        for (k, ver_a) in d.as_object().unwrap() {
-            let ver_a: Version = Version::try_from(ver_a.as_str().unwrap())?;
-            let mut k = k.as_str();
-            if k.contains('"') {
-                k = &k[1..=k.len() - 2];
-            }
+    for (d, file) in package_dev_deps.iter().zip(workspace.packages.iter().map(|p| &p.manifest)) {
+        for (k, ver_a) in d.iter().filter(external) {
+            let ver_a = parse_declared(file, k, ver_a)?;
+            let k = k.as_str();
             dev_dependencies.entry(k).and_modify(|(n, ver_b)| {
                 *n += 1;
                 if ver_a > *ver_b {
@@ -243,164 +400,729 @@ pub fn setup_mono() -> Result<(), Box<dyn Error>> {
             }).or_insert((1, ver_a));
         }
     }
-    let mut mono_package_json = default_package();
+    // The version pinned by hand wins over the ones of the packages
+    for (k, range) in root_pinned.iter() {
+        let Ok(version) = Version::try_from(range.as_str()) else {
+            continue;
+        };
+        dev_dependencies.entry(k.as_str()).or_insert((0, version.clone())).1 = version.clone();
+    }
+    // Internal dependencies always resolve to the package in the workspace
+    for (name, version) in local_packages.iter() {
+        let Ok(version) = Version::try_from(version.as_str()) else {
+            continue;
+        };
+        for synced in [&mut dependencies, &mut dev_dependencies] {
+            synced.entry(name.as_str()).or_insert((0, version.clone())).1 = version.clone();
+        }
+    }
+    // Prefer the versions the packages were tested with in their own CI over the highest range.
+    // The locks are merged into the root lock afterwards
+    let package_locks: Vec<Option<Lockfile>> = workspace.packages.iter_mut().map(|p| p.lock.take()).collect();
+    let mut pinned = prefer_locked_versions(&mut dependencies, &package_deps, &package_locks, &local_packages);
+    pinned.extend(prefer_locked_versions(&mut dev_dependencies, &package_dev_deps, &package_locks, &local_packages));
+    let mut root_lock = Lockfile::default();
+    for lock in package_locks.into_iter().flatten() {
+        root_lock.merge(lock);
+    }
+    for name in local_packages.keys() {
+        root_lock.remove_package(name);
+    }
+    for (name, range, version) in pinned.iter() {
+        root_lock.add_range(name, version, range);
+    }
+    let mut mono_package_json = root_package(config.root_package.as_ref(), &workspace.members());
+    check_peer_dependencies(&package_peer_deps, &dependencies, &dev_dependencies);
+    if !resolutions.is_empty() {
+        // Resolutions of the template take precedence over the lifted ones
+        let fields = mono_package_json.as_object_mut().unwrap();
+        let root_resolutions = fields.entry("resolutions").or_insert_with(|| serde_json::json!({}));
+        if let Some(root_resolutions) = root_resolutions.as_object_mut() {
+            for (k, (v, _)) in resolutions {
+                root_resolutions.entry(k).or_insert(v.into());
+            }
+        }
+    }
+    let nohoist_config = &config.hoist.nohoist;
+    let mut nohoist = BTreeMap::new();
+    if nohoist_config.detect {
+        let used = package_deps.iter().chain(package_dev_deps.iter()).chain([&root_dev_deps]).flat_map(|d| d.keys());
+        nohoist = conflicting_typings(used.map(String::as_str));
+    }
+    nohoist.retain(|name, _| !nohoist_config.ignore.contains(name));
+    let hoisted = select_hoisted(&dev_dependencies, package_dev_deps.len(), &root_pinned, &nohoist, &local_packages, &config.hoist);
     // Sync devDependencies that commont deps are in crate root and all others share the highest
     // version so they are compatable.
-    sync_deps(&mut mono_package_json, dependencies, dev_dependencies)?;
-    update_webpack_config()?;
-    let package_j = serde_json::to_string_pretty(&mono_package_json).unwrap();
-    let mut p = std::fs::canonicalize("../")?;
-    p.push("package.json");
-    std::fs::write(p, package_j)?;
+    setup_notest_scripts(&mut workspace, &mut generated, &config.notest)?;
+    for dep in hoisted.keys().filter(|d| !root_pinned.contains_key(**d)) {
+        mono_package_json["devDependencies"][*dep] = String::from(dev_dependencies[dep].1.clone()).into();
+    }
+    sync_deps(&mut workspace, dependencies, &declared, dev_dependencies, &hoisted, &local_packages, config.workspace_protocol)?;
+    if nohoist_config.detect {
+        nohoist.extend(incompatible_versions(workspace.packages.iter().map(|p| &p.manifest.manifest), &local_packages));
+        nohoist.retain(|name, _| !nohoist_config.ignore.contains(name));
+    }
+    if !nohoist.is_empty() {
+        println!("Not hoisted:");
+    }
+    for (name, reason) in nohoist.iter() {
+        println!("  {name} ({reason})");
+    }
+    let nohoist = nohoist_patterns(&nohoist, nohoist_config);
+    let hoisting = Hoisting::new(&nohoist)?;
+    mono_package_json["workspaces"]["nohoist"] = nohoist.into();
+    update_bundler_configs(&mut workspace, &hoisting)?;
+    update_node_modules_paths(&mut workspace, &hoisting)?;
+    update_tsconfigs(&mut workspace)?;
+    let root_package = merge_root_package(existing_root.as_deref(), generated.root_package.as_ref(), mono_package_json.clone())?;
+    workspace.set_file("package.json", root_package);
+    generated.root_package = Some(mono_package_json);
+    // Seed the root lockfile so the first install resolves what the repos resolved upstream.
+    if workspace.root.join("yarn.lock").exists() {
+        println!("Keeping existing yarn.lock");
+    } else if !root_lock.is_empty() {
+        workspace.set_file("yarn.lock", root_lock.to_yarn_string());
+    }
+    // Nothing is written before every transformation succeeded
+    let written = workspace.commit()?;
+    generated.save(&workspace.root)?;
+    if written.is_empty() {
+        println!("No changes, the monorepo is up to date");
+    } else {
+        println!("Updated:");
+        for path in written {
+            println!("  {}", path.strip_prefix(&workspace.root).unwrap_or(&path).display());
+        }
+    }
     println!("Monorepo setup successful");
     Ok(())
 }
 
+/// Selects the devDependencies that are moved to the monorepo root according to the configured
+/// hoisting policy and prints why each of them was hoisted. Dependencies `pinned` in the root
+/// package.json by hand stay there unless they must never be hoisted, dependencies that must not
+/// be hoisted stay in the packages.
+fn select_hoisted<'a>(
+    dev_dependencies: &HashMap<&'a str, (usize, Version)>,
+    package_count: usize,
+    pinned: &DependencyMap,
+    nohoist: &BTreeMap<String, String>,
+    local_packages: &HashMap<String, String>,
+    policy: &HoistConfig,
+) -> BTreeMap<&'a str, String> {
+    let hoisted: BTreeMap<&str, String> = dev_dependencies
+        .iter()
+        .filter(|(name, _)| !local_packages.contains_key(**name) && !nohoist.contains_key(**name))
+        // `never` also moves dependencies hoisted by an earlier run back to the packages
+        .filter(|(name, _)| !policy.never.iter().any(|n| n == **name))
+        .filter_map(|(name, (used, _))| {
+            let reason = policy.reason(name, *used, package_count);
+            let reason = reason.or_else(|| pinned.contains_key(*name).then(|| "pinned in the root package.json".into()));
+            Some((*name, reason?))
+        })
+        .collect();
+    if !hoisted.is_empty() {
+        println!("Hoisted to the monorepo root:");
+    }
+    for (name, reason) in hoisted.iter() {
+        println!("  {name}@{} ({reason})", String::from(dev_dependencies[name].1.clone()));
+    }
+    hoisted
+}
+
+/// Collects the `resolutions` and `overrides` of a package, overrides are translated to yarn
+/// resolutions since only those are honored by the workspace. On conflicts the higher version wins.
+fn collect_resolutions(resolutions: &mut BTreeMap<String, (String, String)>, manifest: &PackageManifest) {
+    let package = &manifest.name;
+    let mut lifted = Vec::new();
+    if let Some(r) = &manifest.resolutions {
+        lifted.extend(r.iter().map(|(k, r)| (k.clone(), r.clone())));
+    }
+    if let Some(o) = &manifest.overrides {
+        flatten_overrides(o, "", manifest, &mut lifted);
+    }
+    for (key, version) in lifted {
+        match resolutions.entry(key) {
+            Entry::Vacant(e) => {
+                e.insert((version, package.to_string()));
+            }
+            Entry::Occupied(mut e) => {
+                let (current, origin) = e.get();
+                if *current == version {
+                    continue;
+                }
+                let replace = match (Version::try_from(current.as_str()), Version::try_from(version.as_str())) {
+                    (Ok(a), Ok(b)) => b > a,
+                    _ => false,
+                };
+                let kept = if replace { &version } else { current };
+                println!(
+                    "Conflicting resolution for {}: {origin} wants {current}, {package} wants {version}, using {kept}",
+                    e.key()
+                );
+                if replace {
+                    e.insert((version, package.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Flattens npm `overrides` to yarn resolution paths, `$name` references resolve to the version
+/// the package itself depends on.
+fn flatten_overrides(overrides: &Value, prefix: &str, manifest: &PackageManifest, out: &mut Vec<(String, String)>) {
+    let Some(overrides) = overrides.as_object() else {
+        return;
+    };
+    let resolve = |version: &str| match version.strip_prefix('$') {
+        Some(name) => [DependencyKind::Dependencies, DependencyKind::DevDependencies, DependencyKind::PeerDependencies]
+            .iter()
+            .find_map(|kind| manifest.dependencies(*kind)?.get(name))
+            .map_or(version, String::as_str)
+            .to_string(),
+        None => version.to_string(),
+    };
+    for (key, value) in overrides {
+        if key == "." {
+            if let (Some(version), false) = (value.as_str(), prefix.is_empty()) {
+                out.push((prefix.to_string(), resolve(version)));
+            }
+            continue;
+        }
+        // Keys may carry a version selector such as `foo@^1.0.0`
+        let name = split_spec(key).map_or(key.as_str(), |(name, _)| name);
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{prefix}/**/{name}") };
+        match value {
+            Value::String(version) => out.push((path, resolve(version))),
+            Value::Object(_) => flatten_overrides(value, &path, manifest, out),
+            _ => {}
+        }
+    }
+}
 
-fn update_webpack_config() -> Result<(), Box<dyn Error>>{
-    for package in fs::read_dir(fs::canonicalize("../packages")?)? {
-        #[derive(Serialize, Deserialize, Debug)]
-        struct Config {
-            from: String,
+/// Warns about every peer dependency whose range is not satisfied by the version the workspace
+/// provides after syncing.
+fn check_peer_dependencies(
+    package_peer_deps: &[(String, DependencyMap)],
+    dependencies: &HashMap<&str, (usize, Version)>,
+    dev_dependencies: &HashMap<&str, (usize, Version)>,
+) {
+    for (package, peers) in package_peer_deps {
+        for (dep, range) in peers {
+            let Some((_, provided)) = dev_dependencies.get(dep.as_str()).or_else(|| dependencies.get(dep.as_str())) else {
+                continue;
+            };
+            if provided.patch_strategy == PatchStrategy::Major {
+                continue;
+            }
+            let installed = Version {
+                patch_strategy: PatchStrategy::None,
+                ..provided.clone()
+            };
+            let alternatives: Vec<Version> = range.split("||").filter_map(|r| Version::try_from(r.trim()).ok()).collect();
+            if !alternatives.is_empty() && !alternatives.iter().any(|r| r.matches(&installed)) {
+                println!(
+                    "Warning: {package} expects peer {dep}@{range} but the workspace provides {}",
+                    String::from(provided.clone())
+                );
+            }
         }
-        let mut package = package?.path();
-        if !package.is_dir() {
+    }
+}
+
+/// Replaces the synced version of every dependency whose packages share a locked version that
+/// satisfies all their declared ranges, returns the `(name, range, locked version)` of each.
+fn prefer_locked_versions(
+    synced: &mut HashMap<&str, (usize, Version)>,
+    package_deps: &[DependencyMap],
+    package_locks: &[Option<Lockfile>],
+    local_packages: &HashMap<String, String>,
+) -> Vec<(String, String, String)> {
+    let mut pinned = Vec::new();
+    for (name, (_, synced_version)) in synced.iter_mut() {
+        if local_packages.contains_key(*name) {
             continue;
         }
-        package.push("webpack.config.js");
-        let Ok(config) = fs::read_to_string(&package) else {
+        let mut declared = Vec::new();
+        let mut locked = Vec::new();
+        for (deps, lock) in package_deps.iter().zip(package_locks) {
+            let Some(range) = deps.get(*name) else {
+                continue;
+            };
+            let Ok(version) = Version::try_from(range.as_str()) else {
+                continue;
+            };
+            declared.push(version);
+            let locked_version = lock.as_ref().and_then(|l| l.locked_version(name, range));
+            if let Some(Ok(v)) = locked_version.map(Version::try_from) {
+                locked.push(v);
+            }
+        }
+        let Some(shared) = locked
+            .into_iter()
+            .filter(|v| v.patch_strategy == PatchStrategy::None && declared.iter().all(|d| d.matches(v)))
+            .max()
+        else {
             continue;
         };
-        let Some(begin) = config.find("patterns") else {
-            continue;
+        let version = String::from(shared.clone());
+        *synced_version = Version {
+            patch_strategy: synced_version.patch_strategy,
+            ..shared
         };
-        let patterns = &config[begin..];
-        let arr_begin = patterns.find('[').unwrap();
-        let arr_end = patterns.find(']').unwrap();
-        let mut config_array: Vec<Config> = json5::from_str(&patterns[arr_begin..=arr_end])?;
-        for path in config_array.iter_mut() {
-            path.from = format!("../.{}",path.from);
-        }
-        let new_webpack_config = format!("{}{}{}", &config[..begin + arr_begin], json5::to_string(&config_array)?.replace(r"\/", "/"), &config[begin + arr_end + 1..] );
-        fs::write(&package, new_webpack_config)?;
+        pinned.push((name.to_string(), String::from(synced_version.clone()), version));
     }
-    Ok(())
+    pinned
 }
 
-fn setup_notest_script(v: &mut serde_json::Value) -> Result<(), Box<dyn Error>> {
-    let scripts = v.get_mut("scripts").unwrap().as_object_mut().unwrap();
-    if scripts.contains_key("build:notest") {
-        return Ok(());
-    }
-    let build = scripts.get("build").map_or(Err("No build script found"), |r| {
-        Ok(r)
-    })?;
-    let cmds: Vec<String> = build.as_str().unwrap().split("&&").filter_map(|c| {
-        if c.contains("test") {
-            return None;
-        }
-        let mut c = c.to_string();
-        if c.contains("bundle") {
-            c.push_str(":notest");
-        }
-        Some(c)
-    }).collect();
-    let build_notest = cmds.join("&&");
-    scripts.insert("build:notest".into(), build_notest.into());
-    // The bundle script is not mandatory
-    if scripts.contains_key("bundle:notest") || !scripts.contains_key("bundle") {
-        return Ok(());
-    }
-    let bundle = scripts.get("bundle").unwrap();
-    let cmds: Vec<&'_ str> = bundle.as_str().unwrap().split("&&").filter(|c| !c.contains("test")).collect();
-    let bundle_notest = cmds.join("&&");
-    scripts.insert("bundle:notest".into(), bundle_notest.into());
+/// Adds a `build:notest` script to every package, see `setup_notest_script`. The generated
+/// scripts are recorded in `generated`.
+fn setup_notest_scripts(workspace: &mut Workspace, generated: &mut Generated, notest: &NotestConfig) -> Result<(), Box<dyn Error>> {
+    let rules = TestRules::new(notest)?;
+    let previous = std::mem::take(&mut generated.notest_scripts);
+    for package in workspace.packages.iter_mut() {
+        let file = &mut package.manifest;
+        let upstream = package.upstream.as_ref().unwrap_or(&file.manifest).clone();
+        let name = file.manifest.name.clone();
+        let previous = previous.get(&name).map_or(&[][..], Vec::as_slice);
+        let scripts = setup_notest_script(&mut file.manifest, &upstream, &rules, previous).map_err(|message| file.error_at(&["scripts"], message))?;
+        generated.notest_scripts.insert(name, scripts);
+    }
     Ok(())
 }
 
-fn sync_deps(mono_package_json: &mut serde_json::Value, dependencies: HashMap<&str, (usize, Version)>, dev_dependencies: HashMap<&str, (usize, Version)>) -> Result<(), Box<dyn Error>>{
+/// Adds a `build:notest` script that skips the test steps, with the `:notest` variants of the
+/// scripts it runs. Scripts the upstream package defines itself are kept, the ones generated by
+/// the `previous` run are regenerated from the current `build`. Returns the generated scripts.
+fn setup_notest_script(manifest: &mut PackageManifest, upstream: &PackageManifest, rules: &TestRules, previous: &[String]) -> Result<Vec<String>, String> {
+    let upstream_defines = |script: &str| upstream.scripts.as_ref().is_some_and(|s| s.contains_key(script));
+    let scripts = manifest.scripts.get_or_insert_with(Default::default);
+    if !upstream_defines("build:notest") && !scripts.contains_key("build") {
+        return Err(format!("{}: No build script found", manifest.name));
+    }
+    let variants = notest_variants(scripts, &upstream_defines, rules);
+    // Variants generated before that are no longer needed, scripts added by hand are kept
+    scripts.retain(|name, _| !previous.contains(name) || upstream_defines(name) || variants.contains_key(name));
+    let names = variants.keys().cloned().collect();
+    scripts.extend(variants);
+    Ok(names)
+}
+
+fn sync_deps(workspace: &mut Workspace, dependencies: HashMap<&str, (usize, Version)>, declared: &HashMap<&str, Vec<Version>>, dev_dependencies: HashMap<&str, (usize, Version)>, hoisted: &BTreeMap<&str, String>, local_packages: &HashMap<String, String>, workspace_protocol: bool) -> Result<(), Box<dyn Error>>{
     let merge = MERGE_DEPS.get().unwrap();
-    let mono_dev_deps = mono_package_json.get_mut("devDependencies").unwrap().as_object_mut().unwrap();
-    for dep in dev_dependencies.iter().filter(|d| d.1.0 == 10) {
-        mono_dev_deps.insert(dep.0.to_string(), String::from(dep.1.1.clone()).into());
+    if merge.enabled {
+        println!("Merging dependencies:");
     }
-    for package in fs::read_dir(fs::canonicalize("../packages")?)? {
-        let mut package = package?.path();
-        if !package.is_dir() {
-            continue;
-        }
-        package.push("package.json");
-        let mut v: serde_json::Value = serde_json::from_reader(fs::File::open(
-                &package
-                )?)?;
-        setup_notest_script(&mut v)?;
-        if let Some(deps) = v.as_object_mut().unwrap().get_mut("dependencies") {
-            let deps = deps.as_object_mut().unwrap();
-            for dep in deps.iter_mut().filter(|d| d.0.starts_with("@nmshd")) {
-                *dep.1 = String::from(dependencies.get(dep.0.as_str()).unwrap().1.clone()).into();
+    for package in workspace.packages.iter_mut() {
+        let v = &mut package.manifest.manifest;
+        let package_name = v.name.clone();
+        for kind in [DependencyKind::Dependencies, DependencyKind::OptionalDependencies, DependencyKind::DevDependencies] {
+            let upstream = package.upstream.as_ref().and_then(|u| u.dependencies(kind));
+            if let Some(deps) = v.dependencies_mut(kind) {
+                link_internal_dependencies(&package_name, deps, upstream, local_packages, workspace_protocol);
             }
+        }
+        for kind in [DependencyKind::Dependencies, DependencyKind::OptionalDependencies] {
+            let Some(deps) = v.dependencies_mut(kind) else {
+                continue;
+            };
             // if merge flag update the dependencies as well this is optional since it might break the
-            // packages.
-            if *merge {
-                // Update all dependencies to be the highest version in the project.
-                // This ensures all packages use common latest dependencies.
-                for dep in deps.iter_mut() {
-                    *dep.1 = String::from(dependencies.get(dep.0.as_str()).unwrap().1.clone()).into();
-                }
+            // packages. Major changes are only applied with --allow-major.
+            for (dep, range) in deps.iter_mut().filter(|d| !local_packages.contains_key(d.0) && merge.applies_to(d.0)) {
+                let (Some((_, synced)), Ok(current)) = (dependencies.get(dep.as_str()), Version::try_from(range.as_str())) else {
+                    continue;
+                };
+                let Some(target) = merge_target(&current, synced, declared.get(dep.as_str()).map_or(&[], Vec::as_slice), merge.allow_major) else {
+                    continue;
+                };
+                println!(
+                    "  {}: {dep} {} -> {} ({})",
+                    package_name,
+                    String::from(current.clone()),
+                    String::from(target.clone()),
+                    current.change_kind(&target)
+                );
+                *range = String::from(target);
             }
         }
-        let dev_deps = v.as_object_mut().unwrap().get_mut("devDependencies").unwrap().as_object_mut().unwrap();
-        // remove all devDependencies that are now in the package root
-        dev_deps.retain(|k, _| {
-            let mono_dep = dev_dependencies.get(k.as_str()).unwrap();
-            mono_dep.0 != 10
-        });
-        // Update all devDependencies to be the highest version in the project.
-        // This ensures all packages use common latest dependencies.
-        for dev in dev_deps.iter_mut() {
-            *dev.1 = String::from(dev_dependencies.get(dev.0.as_str()).unwrap().1.clone()).into();
+        if let Some(dev_deps) = v.dev_dependencies.as_mut() {
+            // remove all devDependencies that are now in the package root
+            dev_deps.retain(|k, _| !hoisted.contains_key(k.as_str()));
+            // Update all devDependencies to be the highest version in the project.
+            // This ensures all packages use common latest dependencies.
+            for dev in dev_deps.iter_mut().filter(|d| !local_packages.contains_key(d.0)) {
+                if let Some((_, version)) = dev_dependencies.get(dev.0.as_str()) {
+                    *dev.1 = String::from(version.clone());
+                }
+            }
         }
-        fs::write(package, serde_json::to_string_pretty(&v)?)?;
     }
     Ok(())
 }
 
-fn default_package() -> serde_json::Value {
-    serde_json::json!({
-        "name": "monorepo",
-        "version": "0.0.1",
-        "description": "The Enmeshed Monorepo.",
-        "homepage": "https://enmeshed.eu",
-        "license": "MIT",
-        "author": "j&s-soft GmbH",
-        "files": [
-        ],
-        "scripts": {
-        },
-        "private": true,
-        "dependencies": {
-        },
-        "devDependencies": {
-        },
-        "workspaces": {
-            "packages": [
-                "packages/cns-transport",
-                "packages/cns-content",
-                "packages/cns-consumption",
-                "packages/cns-runtime",
-                "packages/cns-app-runtime"
-            ],
-            "nohoist": [
-                r#"**/@types/mocha"#,
-                r#"**/@types/jest**"#
-            ]
-        }
-     })
+/// Moves the devDependencies an earlier run hoisted to the root back to the packages declaring them
+/// upstream, the ones that are still hoisted are removed again by `sync_deps`.
+fn restore_hoisted(workspace: &mut Workspace, root_dev_deps: &DependencyMap) {
+    for package in workspace.packages.iter_mut() {
+        let manifest = &mut package.manifest.manifest;
+        let upstream = package.upstream.as_ref().unwrap_or(manifest);
+        let unhoisted: Vec<(String, String)> = upstream.dev_dependencies.iter().flatten()
+            .filter(|(k, _)| root_dev_deps.contains_key(*k))
+            .filter(|(k, _)| !manifest.dev_dependencies.as_ref().is_some_and(|d| d.contains_key(*k)))
+            .map(|(k, range)| (k.clone(), range.clone()))
+            .collect();
+        if !unhoisted.is_empty() {
+            manifest.dev_dependencies.get_or_insert_with(Default::default).extend(unhoisted);
+        }
+    }
+}
+
+/// Parses a declared dependency range, errors name the manifest and field.
+fn parse_declared(file: &ManifestFile, name: &str, range: &str) -> Result<Version, Diagnostic> {
+    Version::try_from(range).map_err(|_| {
+        let kind = DependencyKind::ALL
+            .into_iter()
+            .find(|kind| file.manifest.dependencies(*kind).and_then(|d| d.get(name)).is_some_and(|r| r == range))
+            .unwrap_or(DependencyKind::Dependencies);
+        file.error_at(&[kind.field(), name], format!("Invalid version range `{range}` of {name}"))
+            .with_note("expected a semver range such as `^1.2.3`, `~1.2.3`, `1.2.x` or `*`")
+    })
+}
+
+/// Points every dependency on a workspace package at its local version, either exactly or with
+/// the `workspace:` protocol, and warns if the local version leaves the range declared `upstream`.
+fn link_internal_dependencies(
+    package: &str,
+    deps: &mut DependencyMap,
+    upstream: Option<&DependencyMap>,
+    local_packages: &HashMap<String, String>,
+    workspace_protocol: bool,
+) {
+    for (dep, range) in deps.iter_mut() {
+        let Some(local) = local_packages.get(dep) else {
+            continue;
+        };
+        // After the first run the range is the local version, the warning is about the original one
+        let declared = upstream.and_then(|u| u.get(dep)).unwrap_or(range);
+        let declared = declared.strip_prefix("workspace:").unwrap_or(declared);
+        if let (Ok(declared), Ok(local)) = (Version::try_from(declared), Version::try_from(local.as_str())) {
+            if !declared.matches(&local) {
+                println!("Warning: {package} declares {dep}@{} but the workspace contains {}", String::from(declared), String::from(local));
+            }
+        }
+        *range = if workspace_protocol { format!("workspace:{local}") } else { local.clone() };
+    }
+}
+
+/// The version `current` is raised to by `--merge`: the highest version of the project, or the
+/// highest compatible one unless major changes are allowed.
+fn merge_target(current: &Version, synced: &Version, declared: &[Version], allow_major: bool) -> Option<Version> {
+    let target = if allow_major || current.is_compatible(synced) {
+        synced.clone()
+    } else {
+        declared.iter().filter(|v| current.is_compatible(v)).max()?.clone()
+    };
+    (target > *current).then_some(target)
+}
+
+/// Merges the generated root manifest into the `existing` one, keeping its formatting. Generated
+/// fields replace the existing ones, objects such as `scripts` or `devDependencies` are merged by
+/// key. Entries the `previous` run generated that are no longer generated are removed, fields
+/// that are not generated are kept.
+fn merge_root_package(existing: Option<&str>, previous: Option<&Value>, generated: Value) -> Result<String, Box<dyn Error>> {
+    let Some(existing) = existing else {
+        return Ok(serde_json::to_string_pretty(&generated)? + "\n");
+    };
+    let old: Value = serde_json::from_str(existing)?;
+    let (Some(old_fields), Value::Object(generated)) = (old.as_object(), generated) else {
+        return Err("The root package.json must be an object".into());
+    };
+    let mut fields = old_fields.clone();
+    for (field, before) in previous.and_then(Value::as_object).into_iter().flatten() {
+        let now = generated.get(field);
+        let stale = match (fields.get_mut(field), before) {
+            (Some(Value::Object(current)), Value::Object(before)) => {
+                let now = now.and_then(Value::as_object);
+                current.retain(|key, _| !before.contains_key(key) || now.is_some_and(|now| now.contains_key(key)));
+                current.is_empty() && now.is_none()
+            }
+            (Some(_), _) => now.is_none(),
+            (None, _) => false,
+        };
+        if stale {
+            fields.remove(field);
+        }
+    }
+    for (field, value) in generated {
+        match (fields.get_mut(&field), value) {
+            (Some(Value::Object(current)), Value::Object(value)) => current.extend(value),
+            (_, value) => {
+                fields.insert(field, value);
+            }
+        }
+    }
+    let mut document = JsonDocument::parse(existing)?;
+    document.update(&old, &Value::Object(fields));
+    Ok(document.as_str().to_string())
+}
+
+/// The root manifest: the template of the config, or a minimal one, with the skeleton of the
+/// generated fields and the package directories as `workspaces`.
+fn root_package(template: Option<&serde_json::Map<String, Value>>, members: &[String]) -> serde_json::Value {
+    let mut root = match template {
+        Some(template) => template.clone(),
+        None => serde_json::json!({
+            "name": "monorepo",
+            "version": "0.0.1",
+        })
+        .as_object()
+        .unwrap()
+        .clone(),
+    };
+    // Workspaces are only supported in private packages
+    root.insert("private".into(), true.into());
+    for field in ["dependencies", "devDependencies"] {
+        root.entry(field).or_insert_with(|| serde_json::json!({}));
+    }
+    root.insert("workspaces".into(), serde_json::json!({
+        "packages": members,
+        "nohoist": []
+    }));
+    root.into()
 }
 
 #[cfg(test)]
 mod test {
-    use super::Version;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+    };
+
+    use crate::{
+        config::{Config, HoistPolicy},
+        lockfile::Lockfile,
+        manifest::{ManifestFile, PackageManifest},
+        scripts::TestRules,
+        workspace::Workspace,
+        MERGE_DEPS,
+    };
+
+    use super::{
+        collect_resolutions, merge_root_package, merge_target, parse_declared, prefer_locked_versions, select_hoisted,
+        setup_notest_script, setup_workspace, ChangeKind, Version,
+    };
+
+    #[test]
+    fn test_setup_reruns_with_workspace_protocol() {
+        let config = Config {
+            workspace_protocol: true,
+            ..Default::default()
+        };
+        MERGE_DEPS.get_or_init(Default::default);
+        let root = std::env::temp_dir().join(format!("monosetup-setup-{}", std::process::id()));
+        let manifest = |dir: &str, content: &str| {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("package.json"), content).unwrap();
+        };
+        manifest("packages/a", r#"{ "name": "@t/a", "version": "2.1.0", "scripts": { "build": "tsc" } }"#);
+        manifest(
+            "packages/b",
+            r#"{ "name": "@t/b", "version": "1.0.0", "scripts": { "build": "tsc" }, "dependencies": { "@t/a": "^2.0.0" } }"#,
+        );
+        let setup = || setup_workspace(Workspace::load(&root, &["packages/*".into()]).unwrap(), &config).unwrap();
+        setup();
+        setup();
+        // No package has a lockfile to seed the root one from
+        assert!(!root.join("yarn.lock").exists());
+        let b = fs::read_to_string(root.join("packages/b/package.json")).unwrap();
+        assert!(b.contains(r#""@t/a": "workspace:2.1.0""#), "{b}");
+        // Still within the declared ^2.0.0
+        manifest("packages/a", r#"{ "name": "@t/a", "version": "2.2.0", "scripts": { "build": "tsc" } }"#);
+        setup();
+        let b = fs::read_to_string(root.join("packages/b/package.json")).unwrap();
+        assert!(b.contains(r#""@t/a": "workspace:2.2.0""#), "{b}");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_setup_reruns_with_stricter_policy() {
+        MERGE_DEPS.get_or_init(Default::default);
+        let root = std::env::temp_dir().join(format!("monosetup-hoist-{}", std::process::id()));
+        let manifest = |dir: &str, content: &str| {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("package.json"), content).unwrap();
+        };
+        let package = |name: &str| {
+            format!(r#"{{ "name": "{name}", "version": "1.0.0", "scripts": {{ "build": "tsc" }}, "devDependencies": {{ "typescript": "^5.1.6" }} }}"#)
+        };
+        manifest("packages/a", &package("a"));
+        manifest("packages/b", &package("b"));
+        manifest("", r#"{ "name": "mono", "devDependencies": { "prettier": "^3.0.0" } }"#);
+        let setup = |config: &Config| setup_workspace(Workspace::load(&root, &["packages/*".into()]).unwrap(), config).unwrap();
+        let read = |path: &str| fs::read_to_string(root.join(path)).unwrap();
+
+        setup(&Config::default());
+        assert!(read("package.json").contains(r#""typescript": "^5.1.6""#));
+        assert!(!read("packages/a/package.json").contains("typescript"));
+
+        let mut config = Config::default();
+        config.hoist.policy = HoistPolicy::AtLeast(3);
+        setup(&config);
+        let root_package = read("package.json");
+        assert!(!root_package.contains("typescript") && root_package.contains("prettier"), "{root_package}");
+        for path in ["packages/a/package.json", "packages/b/package.json"] {
+            assert!(read(path).contains(r#""typescript": "^5.1.6""#));
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_select_hoisted_reasons() {
+        let v = |s: &str| Version::try_from(s).unwrap();
+        // A re-run, the hoisted devDependencies are counted from the upstream manifests again
+        let dev_dependencies = HashMap::from([("typescript", (2, v("^5.1.6"))), ("prettier", (0, v("^3.0.0"))), ("webpack", (2, v("^5.88.0")))]);
+        let pinned = BTreeMap::from([("prettier".to_string(), "^3.0.0".to_string()), ("webpack".to_string(), "^5.88.0".to_string())]).into_iter().collect();
+        let mut config = Config::default();
+        config.hoist.never = vec!["webpack".into()];
+        let hoisted = select_hoisted(&dev_dependencies, 2, &pinned, &BTreeMap::new(), &HashMap::new(), &config.hoist);
+        assert_eq!(
+            hoisted,
+            BTreeMap::from([("prettier", "pinned in the root package.json".to_string()), ("typescript", "used by all 2 packages".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_prefer_locked_versions() {
+        let v = |s: &str| Version::try_from(s).unwrap();
+        let lock = |content: &str| Some(Lockfile::parse_yarn(content).unwrap());
+        let deps = |deps: &[(&str, &str)]| deps.iter().map(|(k, r)| (k.to_string(), r.to_string())).collect();
+        let package_deps = vec![
+            deps(&[("lodash", "^4.17.0"), ("mocha", "^9.0.0"), ("@t/a", "^1.0.0")]),
+            deps(&[("lodash", "^4.17.10"), ("mocha", "^10.0.0")]),
+        ];
+        let package_locks = vec![
+            lock("lodash@^4.17.0:\n  version \"4.17.21\"\n\nmocha@^9.0.0:\n  version \"9.2.2\"\n\n\"@t/a@^1.0.0\":\n  version \"1.0.0\"\n"),
+            lock("lodash@^4.17.10:\n  version \"4.17.15\"\n"),
+        ];
+        let mut synced = HashMap::from([("lodash", (2, v("^4.17.10"))), ("mocha", (2, v("^10.0.0"))), ("@t/a", (1, v("1.1.0")))]);
+        let local_packages = HashMap::from([("@t/a".to_string(), "1.1.0".to_string())]);
+        let pinned = prefer_locked_versions(&mut synced, &package_deps, &package_locks, &local_packages);
+        // The highest locked version every declared range accepts, mocha 9.2.2 is not within ^10.0.0
+        assert_eq!(pinned, vec![("lodash".to_string(), "^4.17.21".to_string(), "4.17.21".to_string())]);
+        assert_eq!(synced["lodash"].1, v("^4.17.21"));
+        assert_eq!(synced["mocha"].1, v("^10.0.0"));
+        assert_eq!(synced["@t/a"].1, v("1.1.0"));
+    }
+
+    #[test]
+    fn test_invalid_range_diagnostic() {
+        let dir = std::env::temp_dir().join(format!("monosetup-range-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("package.json");
+        fs::write(&path, r#"{ "name": "a", "version": "1.0.0", "devDependencies": { "b": "foo" } }"#).unwrap();
+        let file = ManifestFile::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let err = parse_declared(&file, "b", "foo").unwrap_err();
+        assert_eq!(err.message, "Invalid version range `foo` of b");
+        assert_eq!(err.location.unwrap().label.as_deref(), Some("devDependencies.b"));
+        assert_eq!(err.notes, ["expected a semver range such as `^1.2.3`, `~1.2.3`, `1.2.x` or `*`"]);
+    }
+
+    #[test]
+    fn test_merge_target() {
+        let v = |s: &str| Version::try_from(s).unwrap();
+        let declared = vec![v("^1.2.0"), v("^1.4.1"), v("^2.0.0"), v("^0.3.1"), v("^0.4.0")];
+        assert_eq!(merge_target(&v("^1.2.0"), &v("^2.0.0"), &declared, false), Some(v("^1.4.1")));
+        assert_eq!(merge_target(&v("^1.2.0"), &v("^2.0.0"), &declared, true), Some(v("^2.0.0")));
+        assert_eq!(merge_target(&v("^1.4.1"), &v("^2.0.0"), &declared, false), None);
+        assert_eq!(merge_target(&v("^0.3.1"), &v("^2.0.0"), &declared, false), None);
+        assert_eq!(v("^1.2.0").change_kind(&v("^1.4.1")), ChangeKind::Minor);
+        assert_eq!(v("^0.3.1").change_kind(&v("^0.4.0")), ChangeKind::Major);
+        assert_eq!(v("~1.2.0").change_kind(&v("^1.2.0")), ChangeKind::Patch);
+    }
+
+    #[test]
+    fn test_version_matches() {
+        let matches = |range: &str, version: &str| {
+            Version::try_from(range).unwrap().matches(&Version::try_from(version).unwrap())
+        };
+        assert!(matches("^1.2.3", "1.9.0"));
+        assert!(!matches("^1.2.3", "2.0.0"));
+        assert!(matches("^0.2.3", "0.2.9"));
+        assert!(!matches("^0.2.3", "0.3.0"));
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+        assert!(matches("1.x", "1.4.0"));
+        assert!(!matches("1.2.3", "1.2.4"));
+        assert!(!matches("^1.2.3", "1.3.0-beta.1"));
+    }
+
+    #[test]
+    fn test_collect_resolutions() {
+        let mut resolutions = BTreeMap::new();
+        let a = serde_json::json!({
+            "name": "a",
+            "version": "1.0.0",
+            "dependencies": { "bar": "^2.0.0" },
+            "resolutions": { "foo": "1.0.0" },
+            "overrides": { "baz": { ".": "3.0.0", "qux": "$bar" } }
+        });
+        let b = serde_json::json!({ "name": "b", "version": "1.0.0", "overrides": { "foo": "1.2.0" } });
+        collect_resolutions(&mut resolutions, &serde_json::from_value(a).unwrap());
+        collect_resolutions(&mut resolutions, &serde_json::from_value(b).unwrap());
+        let resolutions: Vec<(&str, &str)> = resolutions.iter().map(|(k, (v, _))| (k.as_str(), v.as_str())).collect();
+        assert_eq!(resolutions, vec![("baz", "3.0.0"), ("baz/**/qux", "^2.0.0"), ("foo", "1.2.0")]);
+    }
+
+    #[test]
+    fn test_merge_root_package() {
+        let existing = "{\n  \"name\": \"mono\",\n  \"scripts\": { \"lint\": \"eslint\" },\n  \"resolutions\": { \"a\": \"1.0.0\" }\n}\n";
+        let generated = serde_json::json!({
+            "name": "monorepo",
+            "scripts": { "build": "tsc -b" },
+            "devDependencies": { "mocha": "^10.2.0" }
+        });
+        let merged = merge_root_package(Some(existing), None, generated.clone()).unwrap();
+        assert_eq!(
+            merged,
+            "{\n  \"name\": \"monorepo\",\n  \"scripts\": { \"lint\": \"eslint\", \"build\": \"tsc -b\" },\n  \"resolutions\": { \"a\": \"1.0.0\" },\n  \"devDependencies\": {\n    \"mocha\": \"^10.2.0\"\n  }\n}\n"
+        );
+        assert_eq!(merge_root_package(Some(&merged), Some(&generated), generated.clone()).unwrap(), merged);
+
+        // mocha is no longer hoisted, the resolution was added by hand and stays
+        let next = serde_json::json!({
+            "name": "monorepo",
+            "scripts": { "build": "tsc -b" },
+            "devDependencies": { "typescript": "^5.1.6" }
+        });
+        assert_eq!(
+            merge_root_package(Some(&merged), Some(&generated), next).unwrap(),
+            "{\n  \"name\": \"monorepo\",\n  \"scripts\": { \"lint\": \"eslint\", \"build\": \"tsc -b\" },\n  \"resolutions\": { \"a\": \"1.0.0\" },\n  \"devDependencies\": {\n    \"typescript\": \"^5.1.6\"\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_notest_scripts_added_by_hand_are_kept() {
+        let rules = TestRules::new(&Default::default()).unwrap();
+        let upstream: PackageManifest = serde_json::from_value(serde_json::json!({
+            "name": "a",
+            "version": "1.0.0",
+            "scripts": { "build": "tsc && npm run bundle", "bundle": "webpack && mocha" }
+        }))
+        .unwrap();
+        let mut manifest = upstream.clone();
+        let generated = setup_notest_script(&mut manifest, &upstream, &rules, &[]).unwrap();
+        assert_eq!(generated, ["build:notest", "bundle:notest"]);
+        // The bundle no longer runs tests, and a script was added by hand
+        let scripts = manifest.scripts.as_mut().unwrap();
+        scripts.insert("bundle".into(), "webpack".into());
+        scripts.insert("lint:notest".into(), "eslint".into());
+        let generated = setup_notest_script(&mut manifest, &upstream, &rules, &generated).unwrap();
+        assert_eq!(generated, ["build:notest"]);
+        let scripts = manifest.scripts.unwrap();
+        assert_eq!(scripts["build:notest"], "tsc && npm run bundle");
+        assert!(scripts.contains_key("lint:notest") && !scripts.contains_key("bundle:notest"));
+    }
 
     #[test]
     fn test_version_comp() {
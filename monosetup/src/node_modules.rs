@@ -0,0 +1,372 @@
+use std::{collections::BTreeMap, error::Error, fs, ops::Range, path::Path};
+
+use glob::Pattern;
+use oxc_allocator::Allocator;
+use oxc_ast::ast::{StringLiteral, TemplateElement};
+use oxc_ast_visit::Visit;
+use oxc_parser::Parser;
+use oxc_span::SourceType;
+
+use crate::{
+    bundler::bundler_of,
+    diagnostics::Diagnostic,
+    mono::DependencyKind,
+    tsconfig::relative_path,
+    workspace::{Package, SourceFile, Workspace},
+};
+
+/// A byte range of a text with its replacement, or with the reason it cannot be replaced.
+type Edit = (Range<usize>, String);
+
+/// Decides where the dependencies of a package are installed: in the `node_modules` of the
+/// monorepo root, unless a `nohoist` pattern keeps them in the package.
+pub struct Hoisting {
+    nohoist: Vec<Pattern>,
+}
+
+/// What a path into a package's own `node_modules` names.
+#[derive(Debug, PartialEq)]
+struct Reference {
+    /// The `node_modules/` or `./node_modules/` prefix
+    prefix: Range<usize>,
+    /// The package, scope, `.bin` directory or `.bin/<binary>` following the prefix
+    target: String,
+}
+
+/// A package with the location of the hoisted `node_modules` relative to it.
+pub struct HoistedPackage {
+    name: String,
+    dependencies: Vec<String>,
+    /// The dependency providing each binary in `node_modules/.bin`
+    binaries: BTreeMap<String, String>,
+    /// The path from the package to the monorepo root
+    root: String,
+}
+
+impl HoistedPackage {
+    pub fn new(package: &Package, root: &Path) -> Self {
+        let manifest = &package.manifest.manifest;
+        let dependencies: Vec<String> = DependencyKind::ALL
+            .iter()
+            .filter_map(|kind| manifest.dependencies(*kind))
+            .flat_map(|deps| deps.keys().cloned())
+            .collect();
+        Self {
+            name: manifest.name.clone(),
+            binaries: binaries(&[&package.dir, root], &dependencies),
+            dependencies,
+            root: relative_path(&package.dir, root),
+        }
+    }
+}
+
+/// The binaries of the `dependencies` from the `bin` field of their package.json, installed in the
+/// `node_modules` of one of the `dirs`. A dependency that is not installed yet is assumed to provide
+/// a binary named like itself.
+fn binaries(dirs: &[&Path], dependencies: &[String]) -> BTreeMap<String, String> {
+    let mut binaries = BTreeMap::new();
+    for dependency in dependencies {
+        let unscoped = dependency.rsplit('/').next().unwrap_or(dependency);
+        let installed = dirs.iter().find_map(|dir| {
+            let manifest = fs::read_to_string(dir.join("node_modules").join(dependency).join("package.json")).ok()?;
+            serde_json::from_str::<serde_json::Value>(&manifest).ok()
+        });
+        let names = match installed.as_ref().map(|m| &m["bin"]) {
+            Some(serde_json::Value::Object(bin)) => bin.keys().cloned().collect(),
+            Some(serde_json::Value::String(_)) | None => vec![unscoped.to_string()],
+            Some(_) => Vec::new(),
+        };
+        for name in names {
+            binaries.entry(name).or_insert_with(|| dependency.clone());
+        }
+    }
+    binaries
+}
+
+impl Hoisting {
+    pub fn new(nohoist: &[String]) -> Result<Self, Box<dyn Error>> {
+        let nohoist = nohoist
+            .iter()
+            .map(|p| Pattern::new(p).map_err(|e| format!("Invalid nohoist pattern `{p}`: {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { nohoist })
+    }
+
+    fn is_hoisted(&self, package: &HoistedPackage, dependency: &str) -> bool {
+        let path = format!("{}/{dependency}", package.name);
+        !self.nohoist.iter().any(|p| p.matches(&path))
+    }
+
+    /// The edits that point the references to the package's own `node_modules` in `text` to the
+    /// `node_modules` of the root, for dependencies that are hoisted. Binaries follow the dependency
+    /// providing them. A scope or `.bin` that is only partly hoisted cannot be retargeted, these
+    /// references are returned with the reason.
+    pub fn edits(&self, package: &HoistedPackage, text: &str) -> (Vec<Edit>, Vec<Edit>) {
+        let (mut edits, mut unresolved) = (Vec::new(), Vec::new());
+        for reference in references(text) {
+            let hoisted = match reference.target.as_str() {
+                ".bin" => {
+                    let mut kept: Vec<&str> = package
+                        .binaries
+                        .values()
+                        .filter(|d| !self.is_hoisted(package, d))
+                        .map(String::as_str)
+                        .collect();
+                    kept.dedup();
+                    if !kept.is_empty() {
+                        let reason = format!("the binaries of {} are not hoisted, the rest of .bin is", kept.join(", "));
+                        unresolved.push((reference.prefix.start..reference.prefix.end + ".bin".len(), reason));
+                        continue;
+                    }
+                    true
+                }
+                bin if bin.starts_with(".bin/") => match package.binaries.get(&bin[".bin/".len()..]) {
+                    Some(dependency) => self.is_hoisted(package, dependency),
+                    None => package.dependencies.iter().all(|d| self.is_hoisted(package, d)),
+                },
+                scope if scope.starts_with('@') && !scope.contains('/') => {
+                    let in_scope = package
+                        .dependencies
+                        .iter()
+                        .filter(|d| d.starts_with(&format!("{scope}/")));
+                    let kept: Vec<&String> = in_scope.filter(|d| !self.is_hoisted(package, d)).collect();
+                    if !kept.is_empty() {
+                        let kept: Vec<&str> = kept.iter().map(|d| d.as_str()).collect();
+                        let reason = format!("{} is not hoisted, the rest of {scope} is", kept.join(", "));
+                        unresolved.push((reference.prefix.start..reference.prefix.end + scope.len(), reason));
+                        continue;
+                    }
+                    true
+                }
+                dependency => self.is_hoisted(package, dependency),
+            };
+            if hoisted {
+                edits.push((reference.prefix, format!("{}/node_modules/", package.root)));
+            }
+        }
+        (edits, unresolved)
+    }
+}
+
+/// Finds the paths in `text` that start with `node_modules/` or `./node_modules/`, i.e. point into
+/// the `node_modules` next to the file. Paths such as `../node_modules/` or `**/node_modules/` do not.
+fn references(text: &str) -> Vec<Reference> {
+    const PREFIX: &str = "node_modules/";
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(i) = text[from..].find(PREFIX) {
+        let at = from + i;
+        from = at + PREFIX.len();
+        let start = if text[..at].ends_with("./") && !text[..at].ends_with("../") {
+            at - 2
+        } else {
+            at
+        };
+        if text[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| !c.is_whitespace() && !"\"'`[(,=:{".contains(c))
+        {
+            continue;
+        }
+        let rest = &text[from..];
+        // `:` separates the paths of a list such as `PATH`
+        let end = rest
+            .find(|c: char| c.is_whitespace() || "\"'`,;:)]}&|".contains(c))
+            .unwrap_or(rest.len());
+        let mut segments = rest[..end].split('/');
+        let first = segments.next().unwrap_or_default();
+        let target = match segments.next() {
+            Some(name) if (first.starts_with('@') || first == ".bin") && !name.is_empty() => format!("{first}/{name}"),
+            _ => first.to_string(),
+        };
+        if target.is_empty() || target.contains('*') {
+            continue;
+        }
+        found.push(Reference {
+            prefix: start..from,
+            target,
+        });
+    }
+    found
+}
+
+/// Points the paths into a package's own `node_modules` to where the dependency is installed after
+/// hoisting, in the package scripts and in the config files of the package: JavaScript and
+/// TypeScript string literals, JSON, JSON5 and YAML files. The bundler configs are updated by
+/// `bundler::update_bundler_configs`.
+pub fn update_node_modules_paths(workspace: &mut Workspace, hoisting: &Hoisting) -> Result<(), Box<dyn Error>> {
+    let root = workspace.root.clone();
+    for package in workspace.packages.iter_mut() {
+        let hoisted = HoistedPackage::new(package, &root);
+        for file in package.tsconfig.iter_mut().chain(package.configs.iter_mut()) {
+            update_file(file, &hoisted, hoisting);
+        }
+        let file = &mut package.manifest;
+        let mut warnings = Vec::new();
+        for (name, script) in file.manifest.scripts.iter_mut().flatten() {
+            let (edits, unresolved) = hoisting.edits(&hoisted, script);
+            apply(script, edits);
+            warnings.extend(unresolved.into_iter().map(|(_, reason)| (name.clone(), reason)));
+        }
+        for (name, reason) in warnings {
+            let mut warning = file.error_at(
+                &["scripts", &name],
+                "Cannot point this path to the hoisted node_modules",
+            );
+            warning.warning = true;
+            println!("{}", warning.with_note(reason));
+        }
+    }
+    Ok(())
+}
+
+/// Points the paths into the package's `node_modules` in `file` to the hoisted ones, files that fail
+/// to parse are skipped with a warning.
+pub fn update_file(file: &mut SourceFile, package: &HoistedPackage, hoisting: &Hoisting) {
+    let (mut edits, unresolved) = hoisting.edits(package, &file.content);
+    let extension = file.path.extension().unwrap_or_default().to_string_lossy();
+    if matches!(extension.as_ref(), "js" | "cjs" | "mjs" | "ts" | "cts" | "mts") {
+        // Only string literals are paths, not comments or regular expressions
+        let literals = match string_literals(&file.path, &file.content) {
+            Ok(literals) => literals,
+            Err(warning) => return println!("{warning}"),
+        };
+        edits.retain(|(range, _)| literals.iter().any(|l| l.start <= range.start && range.end <= l.end));
+    }
+    for (range, reason) in unresolved {
+        let warning = Diagnostic::warning("Cannot point this path to the hoisted node_modules").at(
+            &file.path,
+            &file.content,
+            range,
+            reason,
+        );
+        println!("{warning}");
+    }
+    apply(&mut file.content, edits);
+}
+
+/// Applies `edits` sorted by position, back to front so the earlier ranges stay valid.
+fn apply(text: &mut String, edits: Vec<Edit>) {
+    for (range, replacement) in edits.into_iter().rev() {
+        text.replace_range(range, &replacement);
+    }
+}
+
+/// The byte ranges of the string literals and template parts of a JavaScript or TypeScript file.
+fn string_literals(path: &Path, source: &str) -> Result<Vec<Range<usize>>, Diagnostic> {
+    let allocator = Allocator::default();
+    let source_type = SourceType::from_path(path).unwrap_or_default();
+    let parsed = Parser::new(&allocator, source, source_type).parse();
+    if let Some(error) = parsed.errors.first() {
+        return Err(Diagnostic::warning(format!("Skipping a config that failed to parse: {error}")).in_file(path));
+    }
+    let mut literals = Literals::default();
+    literals.visit_program(&parsed.program);
+    Ok(literals.ranges)
+}
+
+#[derive(Default)]
+struct Literals {
+    ranges: Vec<Range<usize>>,
+}
+
+impl<'a> Visit<'a> for Literals {
+    fn visit_string_literal(&mut self, it: &StringLiteral<'a>) {
+        self.ranges.push(it.span.start as usize..it.span.end as usize);
+    }
+
+    fn visit_template_element(&mut self, it: &TemplateElement<'a>) {
+        self.ranges.push(it.span.start as usize..it.span.end as usize);
+    }
+}
+
+/// Checks if a file at the root of a package is a config file the paths are rewritten in, e.g.
+/// `karma.conf.js`, `jest.config.ts`, `tsconfig.test.json` or `.mocharc.yml`.
+pub fn is_config(name: &str) -> bool {
+    let Some((stem, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    let known = matches!(
+        extension,
+        "js" | "cjs" | "mjs" | "ts" | "cts" | "mts" | "json" | "json5" | "yml" | "yaml"
+    );
+    let config = stem.contains("config") || stem.contains(".conf") || stem.starts_with('.');
+    // Files monosetup reads on its own
+    let own = ["package.json", "package-lock.json", "tsconfig.json"].contains(&name) || bundler_of(name).is_some();
+    known && config && !own && !stem.ends_with(".d")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, fs};
+
+    use super::{binaries, is_config, references, HoistedPackage, Hoisting};
+
+    #[test]
+    fn test_references() {
+        let targets = |text: &str| references(text).into_iter().map(|r| r.target).collect::<Vec<_>>();
+        assert_eq!(
+            targets("cp ./node_modules/a/b.js dist && node_modules/.bin/tsc"),
+            vec!["a", ".bin/tsc"]
+        );
+        assert_eq!(
+            targets(r#"["node_modules/@types", 'node_modules/@types/mocha/x.d.ts']"#),
+            vec!["@types", "@types/mocha"]
+        );
+        assert!(
+            targets(r#"["node_modules", "../node_modules/a", "**/node_modules/**", "x/node_modules/a"]"#).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_edits() {
+        let hoisting = Hoisting::new(&["**/@types/mocha".into()]).unwrap();
+        let package = HoistedPackage {
+            name: "@nmshd/transport".into(),
+            dependencies: vec!["@types/mocha".into(), "@types/node".into(), "lodash".into()],
+            binaries: BTreeMap::new(),
+            root: "../..".into(),
+        };
+        let text = "files: ['./node_modules/lodash/lodash.js', 'node_modules/@types/mocha/index.d.ts']\ntypeRoots: ./node_modules/@types";
+        let (edits, unresolved) = hoisting.edits(&package, text);
+        let mut text = text.to_string();
+        super::apply(&mut text, edits);
+        assert_eq!(
+            text,
+            "files: ['../../node_modules/lodash/lodash.js', 'node_modules/@types/mocha/index.d.ts']\ntypeRoots: ./node_modules/@types"
+        );
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(unresolved[0].1, "@types/mocha is not hoisted, the rest of @types is");
+
+        assert!(is_config("karma.conf.js") && is_config("tsconfig.test.json") && is_config(".mocharc.yml"));
+    }
+
+    #[test]
+    fn test_binaries_follow_their_package() {
+        let root = std::env::temp_dir().join(format!("monosetup-binaries-{}", std::process::id()));
+        let installed = root.join("packages/a/node_modules/typescript");
+        fs::create_dir_all(&installed).unwrap();
+        fs::write(installed.join("package.json"), r#"{ "bin": { "tsc": "bin/tsc", "tsserver": "bin/tsserver" } }"#).unwrap();
+        let dependencies = vec!["typescript".to_string(), "mocha".to_string()];
+        let binaries = binaries(&[&root.join("packages/a"), &root], &dependencies);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(binaries["tsc"], "typescript");
+        assert_eq!(binaries["mocha"], "mocha");
+
+        let hoisting = Hoisting::new(&["a/typescript".into()]).unwrap();
+        let package = HoistedPackage {
+            name: "a".into(),
+            dependencies,
+            binaries,
+            root: "../..".into(),
+        };
+        let text = "node_modules/.bin/tsc && node_modules/.bin/mocha && PATH=node_modules/.bin:$PATH";
+        let (edits, unresolved) = hoisting.edits(&package, text);
+        let mut text = text.to_string();
+        super::apply(&mut text, edits);
+        assert_eq!(text, "node_modules/.bin/tsc && ../../node_modules/.bin/mocha && PATH=node_modules/.bin:$PATH");
+        assert_eq!(unresolved[0].1, "the binaries of typescript are not hoisted, the rest of .bin is");
+        assert!(!is_config("index.js") && !is_config("package.json") && !is_config("vite.config.ts"));
+    }
+}
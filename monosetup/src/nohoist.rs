@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    config::NohoistConfig,
+    manifest::PackageManifest,
+    mono::{DependencyKind, Version},
+};
+
+/// Type packages declaring the globals of a test framework, they clash when more than one of them
+/// is visible to the compiler.
+const GLOBAL_TYPINGS: [&str; 4] = ["@types/mocha", "@types/jest", "@types/jasmine", "@types/qunit"];
+
+/// The global typings used together in the workspace, with the typings each one conflicts with.
+pub fn conflicting_typings<'a>(dependencies: impl Iterator<Item = &'a str>) -> BTreeMap<String, String> {
+    let used: BTreeSet<&str> = dependencies.filter(|d| GLOBAL_TYPINGS.contains(d)).collect();
+    if used.len() < 2 {
+        return BTreeMap::new();
+    }
+    used.iter()
+        .map(|name| {
+            let others: Vec<&str> = used.iter().copied().filter(|o| o != name).collect();
+            (
+                name.to_string(),
+                format!("its globals conflict with {}", others.join(", ")),
+            )
+        })
+        .collect()
+}
+
+/// The dependencies the packages still declare with incompatible versions after syncing, each
+/// package has to install its own version of them.
+pub fn incompatible_versions<'a>(
+    manifests: impl Iterator<Item = &'a PackageManifest>,
+    local_packages: &HashMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut versions: BTreeMap<&str, Vec<(Version, &str)>> = BTreeMap::new();
+    for manifest in manifests {
+        for kind in [
+            DependencyKind::Dependencies,
+            DependencyKind::OptionalDependencies,
+            DependencyKind::DevDependencies,
+        ] {
+            for (name, range) in manifest.dependencies(kind).into_iter().flatten() {
+                if local_packages.contains_key(name) {
+                    continue;
+                }
+                if let Ok(version) = Version::try_from(range.as_str()) {
+                    versions.entry(name).or_default().push((version, range));
+                }
+            }
+        }
+    }
+    let mut incompatible = BTreeMap::new();
+    for (name, mut versions) in versions {
+        versions.sort();
+        versions.dedup_by(|a, b| a.1 == b.1);
+        let (Some((lowest, _)), Some((highest, _))) = (versions.first(), versions.last()) else {
+            continue;
+        };
+        if !lowest.is_compatible(highest) {
+            let ranges: Vec<&str> = versions.iter().map(|(_, range)| *range).collect();
+            incompatible.insert(name.to_string(), format!("incompatible versions {}", ranges.join(", ")));
+        }
+    }
+    incompatible
+}
+
+/// The `nohoist` patterns for the `detected` dependencies and the configured patterns.
+pub fn nohoist_patterns(detected: &BTreeMap<String, String>, config: &NohoistConfig) -> Vec<String> {
+    let mut patterns: Vec<String> = detected.keys().map(|name| format!("**/{name}")).collect();
+    for pattern in config.patterns.iter() {
+        if !patterns.contains(pattern) {
+            patterns.push(pattern.clone());
+        }
+    }
+    patterns
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::manifest::PackageManifest;
+
+    use super::{conflicting_typings, incompatible_versions};
+
+    #[test]
+    fn test_detection() {
+        assert!(conflicting_typings(["@types/mocha", "@types/node", "@types/mocha"].into_iter()).is_empty());
+        let typings = conflicting_typings(["@types/mocha", "@types/jest", "@types/node"].into_iter());
+        assert_eq!(typings.keys().collect::<Vec<_>>(), ["@types/jest", "@types/mocha"]);
+        assert_eq!(typings["@types/jest"], "its globals conflict with @types/mocha");
+
+        let manifest = |deps: serde_json::Value| -> PackageManifest {
+            serde_json::from_value(serde_json::json!({ "name": "a", "version": "1.0.0", "dependencies": deps }))
+                .unwrap()
+        };
+        let manifests = [
+            manifest(serde_json::json!({ "lodash": "^3.10.0", "axios": "^1.2.0", "b": "1.0.0" })),
+            manifest(serde_json::json!({ "lodash": "^4.17.21", "axios": "^1.4.0", "b": "2.0.0" })),
+        ];
+        let local = HashMap::from([("b".to_string(), "2.0.0".to_string())]);
+        let incompatible = incompatible_versions(manifests.iter(), &local);
+        assert_eq!(incompatible.keys().collect::<Vec<_>>(), ["lodash"]);
+        assert_eq!(incompatible["lodash"], "incompatible versions ^3.10.0, ^4.17.21");
+    }
+}
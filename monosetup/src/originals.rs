@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::workspace::write_files;
+
+/// Directory in the monorepo root where monosetup keeps its state.
+pub const STATE_DIR: &str = ".monosetup";
+/// The upstream version of every rewritten file, stored under its path relative to the root.
+const ORIGINALS_DIR: &str = ".monosetup/originals";
+/// The files monosetup created, they are removed by `eject`.
+const CREATED_FILE: &str = ".monosetup/created.json";
+
+/// What the last `setup` generated, to tell generated entries from the ones added by hand.
+const GENERATED_FILE: &str = ".monosetup/generated.json";
+
+/// The content monosetup generated in a run, see `GENERATED_FILE`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Generated {
+    /// The generated root package.json before it was merged into the existing one
+    pub root_package: Option<Value>,
+    /// The `:notest` scripts generated in each package, by package name
+    pub notest_scripts: BTreeMap<String, Vec<String>>,
+}
+
+impl Generated {
+    /// Loads what the last run generated, nothing if it was not recorded.
+    pub fn load(root: &Path) -> Result<Self, Box<dyn Error>> {
+        match fs::read_to_string(root.join(GENERATED_FILE)) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), Box<dyn Error>> {
+        if Self::load(root).is_ok_and(|saved| saved == *self) {
+            return Ok(());
+        }
+        fs::create_dir_all(root.join(STATE_DIR))?;
+        fs::write(root.join(GENERATED_FILE), serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
+
+/// Stores the original content of the files about to be rewritten by `changes`. Only the first
+/// version is kept, so files rewritten by a later run still restore to their upstream content.
+pub fn record(root: &Path, changes: &[(PathBuf, Option<&str>, String)]) -> Result<(), Box<dyn Error>> {
+    let mut created = load_created(root)?;
+    let created_count = created.len();
+    for (path, original, _) in changes {
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        if created.iter().any(|c| c == relative) {
+            continue;
+        }
+        match original {
+            Some(original) => {
+                let stored = root.join(ORIGINALS_DIR).join(relative);
+                if !stored.exists() {
+                    fs::create_dir_all(stored.parent().unwrap())?;
+                    fs::write(stored, original)?;
+                }
+            }
+            None => created.push(relative.to_path_buf()),
+        }
+    }
+    if created.len() != created_count {
+        fs::create_dir_all(root.join(STATE_DIR))?;
+        fs::write(root.join(CREATED_FILE), serde_json::to_string_pretty(&created)?)?;
+    }
+    Ok(())
+}
+
+/// Restores every file monosetup rewrote and removes the files it created, returns the paths of
+/// the restored and the removed files.
+pub fn eject(root: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Box<dyn Error>> {
+    let created = load_created(root)?;
+    let originals = root.join(ORIGINALS_DIR);
+    let mut stored = Vec::new();
+    if originals.is_dir() {
+        collect_files(&originals, &mut stored)?;
+        stored.sort();
+    }
+    if stored.is_empty() && created.is_empty() {
+        return Err("Nothing to eject, monosetup has not rewritten any files".into());
+    }
+    let mut current = Vec::with_capacity(stored.len());
+    let mut restores = Vec::with_capacity(stored.len());
+    for file in stored.iter() {
+        let path = root.join(file.strip_prefix(&originals)?);
+        current.push(fs::read_to_string(&path).ok());
+        restores.push((path, fs::read_to_string(file)?));
+    }
+    let changes: Vec<(PathBuf, Option<&str>, String)> = restores
+        .into_iter()
+        .zip(current.iter())
+        .map(|((path, original), current)| (path, current.as_deref(), original))
+        .collect();
+    write_files(&changes)?;
+    let mut removed = Vec::new();
+    for path in created.iter().map(|c| root.join(c)) {
+        if path.is_file() {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    fs::remove_dir_all(root.join(STATE_DIR))?;
+    Ok((changes.into_iter().map(|(path, _, _)| path).collect(), removed))
+}
+
+/// The upstream content of the file at `path`, if monosetup rewrote it.
+pub fn load(root: &Path, path: &Path) -> Option<String> {
+    fs::read_to_string(root.join(ORIGINALS_DIR).join(path.strip_prefix(root).ok()?)).ok()
+}
+
+/// The files monosetup created, relative to the root.
+pub fn load_created(root: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    match fs::read_to_string(root.join(CREATED_FILE)) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::workspace::Workspace;
+
+    use super::eject;
+
+    #[test]
+    fn test_eject_restores_originals() {
+        let root = std::env::temp_dir().join(format!("monosetup-originals-{}", std::process::id()));
+        let manifest = "{\n  \"name\": \"a\",\n  \"version\": \"1.0.0\"\n}\n";
+        fs::create_dir_all(root.join("packages/a")).unwrap();
+        fs::write(root.join("packages/a/package.json"), manifest).unwrap();
+
+        // Two runs, the second one must not store the rewritten manifest as the original
+        for version in ["1.1.0", "1.2.0"] {
+            let mut workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+            workspace.packages[0].manifest.manifest.version = version.into();
+            workspace.set_file("package.json", format!("{{ \"version\": \"{version}\" }}\n"));
+            workspace.commit().unwrap();
+        }
+        let (restored, removed) = eject(&root).unwrap();
+        assert_eq!(restored, vec![root.join("packages/a/package.json")]);
+        assert_eq!(removed, vec![root.join("package.json")]);
+        assert_eq!(
+            fs::read_to_string(root.join("packages/a/package.json")).unwrap(),
+            manifest
+        );
+        assert!(!root.join(".monosetup").exists());
+        assert!(eject(&root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{cache::BuildCache, graph::PackageGraph, workspace::Workspace};
+
+/// What happened to the packages of a scheduled run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Outcome {
+    pub succeeded: Vec<usize>,
+    pub failed: Vec<usize>,
+    /// Packages that were not run because a package they depend on failed
+    pub skipped: Vec<usize>,
+    /// Packages that do not define the script, see `run_script`
+    pub missing: Vec<usize>,
+}
+
+/// Runs `task` for the `selected` packages of `graph`. A package starts once every selected
+/// package it depends on succeeded, at most `jobs` run at the same time. The dependents of a
+/// failed package are skipped, independent packages keep running.
+pub fn schedule(
+    graph: &PackageGraph,
+    selected: &BTreeSet<usize>,
+    jobs: usize,
+    task: impl Fn(usize) -> bool + Sync,
+) -> Result<Outcome, Box<dyn Error>> {
+    let order: Vec<usize> = graph
+        .topological_order()?
+        .into_iter()
+        .filter(|i| selected.contains(i))
+        .collect();
+    let mut pending: BTreeSet<usize> = selected.clone();
+    let mut outcome = Outcome::default();
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|s| {
+        let mut running = 0;
+        loop {
+            for i in order.iter().copied() {
+                if running >= jobs.max(1) {
+                    break;
+                }
+                let ready = graph.dependencies[i]
+                    .iter()
+                    .all(|d| !selected.contains(d) || outcome.succeeded.contains(d));
+                if pending.contains(&i) && ready {
+                    pending.remove(&i);
+                    running += 1;
+                    let (tx, task) = (tx.clone(), &task);
+                    s.spawn(move || tx.send((i, task(i))));
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let (i, success) = rx.recv().unwrap();
+            running -= 1;
+            if success {
+                outcome.succeeded.push(i);
+                continue;
+            }
+            outcome.failed.push(i);
+            let mut dependents: Vec<usize> = graph.dependents(i).collect();
+            while let Some(d) = dependents.pop() {
+                if pending.remove(&d) {
+                    outcome.skipped.push(d);
+                    dependents.extend(graph.dependents(d));
+                }
+            }
+        }
+    });
+    Ok(outcome)
+}
+
+/// The build cache and the input hash of every package, packages whose inputs did not change
+/// since their last successful run are skipped.
+pub struct Cached<'a> {
+    pub cache: &'a BuildCache,
+    pub hashes: Vec<String>,
+    /// Run every package even if it is up to date
+    pub force: bool,
+}
+
+/// Runs `script` in the `selected` packages with `schedule`, the output of each package is
+/// prefixed with its name. Packages without the script are skipped. Setting `cancel` kills the
+/// running scripts and fails the packages that did not finish.
+pub fn run_script(
+    workspace: &Workspace,
+    graph: &PackageGraph,
+    selected: &BTreeSet<usize>,
+    script: &str,
+    jobs: usize,
+    cached: Option<&Cached>,
+    cancel: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let missing = Mutex::new(Vec::new());
+    let mut outcome = schedule(graph, selected, jobs, |i| {
+        if cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        let package = &workspace.packages[i];
+        let name = &graph.names[i];
+        if let Some(Cached {
+            cache,
+            hashes,
+            force: false,
+        }) = cached
+        {
+            if cache.is_built(name, &package.dir, &hashes[i]) {
+                println!("[{name}] Up to date");
+                return true;
+            }
+            match cache.restore(&package.dir, &hashes[i]) {
+                Ok(true) => {
+                    println!("[{name}] Restored from cache");
+                    return record(cache, name, &package.dir, &hashes[i]);
+                }
+                Ok(false) => {}
+                Err(e) => println!("[{name}] Failed to restore from cache: {e}"),
+            }
+        }
+        if !package
+            .manifest
+            .manifest
+            .scripts
+            .as_ref()
+            .is_some_and(|s| s.contains_key(script))
+        {
+            println!("[{name}] No {script} script, skipped");
+            missing.lock().unwrap().push(i);
+            return true;
+        }
+        match run_prefixed(&package.dir, &format!("yarn run {script}"), name, cancel) {
+            Ok(true) => cached.is_none_or(|c| record(c.cache, name, &package.dir, &c.hashes[i])),
+            Ok(false) => false,
+            Err(e) => {
+                println!("[{name}] Failed to run {script}: {e}");
+                false
+            }
+        }
+    })?;
+    if let Some(cached) = cached {
+        cached.cache.save()?;
+    }
+    if cancel.load(Ordering::Relaxed) {
+        return Err(format!("{script} cancelled").into());
+    }
+    outcome.missing = missing.into_inner().unwrap();
+    outcome.succeeded.retain(|i| !outcome.missing.contains(i));
+    report(graph, script, &outcome)
+}
+
+fn record(cache: &BuildCache, name: &str, dir: &Path, hash: &str) -> bool {
+    if let Err(e) = cache.record(name, dir, hash) {
+        println!("[{name}] Failed to update the build cache: {e}");
+    }
+    true
+}
+
+/// Prints the result of a run, fails if any package failed.
+pub fn report(graph: &PackageGraph, script: &str, outcome: &Outcome) -> Result<(), Box<dyn Error>> {
+    let names = |packages: &[usize]| {
+        packages
+            .iter()
+            .map(|i| graph.names[*i].as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if outcome.failed.is_empty() && outcome.succeeded.is_empty() && !outcome.missing.is_empty() {
+        return Err(format!("No selected package defines a {script} script").into());
+    }
+    if !outcome.missing.is_empty() {
+        println!("Skipped because they do not define {script}: {}", names(&outcome.missing));
+    }
+    if outcome.failed.is_empty() {
+        println!("{script} succeeded in {} packages", outcome.succeeded.len());
+        return Ok(());
+    }
+    if !outcome.skipped.is_empty() {
+        println!("Skipped because a dependency failed: {}", names(&outcome.skipped));
+    }
+    Err(format!("{script} failed in {}", names(&outcome.failed)).into())
+}
+
+/// Runs `command` in a shell in `dir` and prints its output line by line with the `prefix`. The
+/// command and the processes it started are killed once `cancel` is set.
+pub fn run_prefixed(dir: &Path, command: &str, prefix: &str, cancel: &AtomicBool) -> Result<bool, Box<dyn Error>> {
+    let mut child = if cfg!(target_os = "windows") {
+        Command::new("cmd")
+            .args(["/C", command])
+            .current_dir(dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?
+    } else {
+        let mut shell = Command::new("sh");
+        // A process group of its own, so cancelling kills the processes started by the script
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut shell, 0);
+        shell
+            .args(["-c", command])
+            .current_dir(dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?
+    };
+    let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    thread::scope(|s| {
+        s.spawn(|| forward(stdout, prefix, false));
+        s.spawn(|| forward(stderr, prefix, true));
+        let mut killed = false;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success() && !killed);
+            }
+            if !killed && cancel.load(Ordering::Relaxed) {
+                kill_tree(child.id());
+                killed = true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    })
+}
+
+/// Kills the process `id` and everything it started.
+fn kill_tree(id: u32) {
+    let (program, args) = if cfg!(target_os = "windows") {
+        ("taskkill", vec!["/T".to_string(), "/F".into(), "/PID".into(), id.to_string()])
+    } else {
+        ("kill", vec!["-TERM".to_string(), "--".into(), format!("-{id}")])
+    };
+    let _ = Command::new(program).args(args).stdout(Stdio::null()).stderr(Stdio::null()).status();
+}
+
+fn forward(output: impl Read, prefix: &str, stderr: bool) {
+    for line in BufReader::new(output).lines().map_while(Result::ok) {
+        if stderr {
+            eprintln!("[{prefix}] {line}");
+        } else {
+            println!("[{prefix}] {line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use crate::graph::PackageGraph;
+
+    use super::{report, run_prefixed, schedule, Outcome};
+
+    #[test]
+    fn test_schedule() {
+        // 0 <- 1 <- 3, 0 <- 2, 4 is independent
+        let graph = PackageGraph {
+            names: (0..5).map(|i| format!("p{i}")).collect(),
+            dependencies: vec![
+                BTreeSet::new(),
+                BTreeSet::from([0]),
+                BTreeSet::from([0]),
+                BTreeSet::from([1]),
+                BTreeSet::new(),
+            ],
+        };
+        let all: BTreeSet<usize> = (0..5).collect();
+        let started = Mutex::new(Vec::new());
+        let (running, max_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let outcome = schedule(&graph, &all, 2, |i| {
+            started.lock().unwrap().push(i);
+            max_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            true
+        })
+        .unwrap();
+        assert_eq!(outcome.succeeded.len(), 5);
+        assert_eq!(max_running.into_inner(), 2);
+        let started = started.into_inner().unwrap();
+        let position = |p: usize| started.iter().position(|s| *s == p).unwrap();
+        assert!(position(0) < position(1) && position(1) < position(3) && position(0) < position(2));
+
+        let outcome = schedule(&graph, &all, 4, |i| i != 1).unwrap();
+        assert_eq!(outcome.failed, vec![1]);
+        assert_eq!(outcome.skipped, vec![3]);
+        let succeeded: BTreeSet<usize> = outcome.succeeded.into_iter().collect();
+        assert_eq!(succeeded, BTreeSet::from([0, 2, 4]));
+    }
+
+    #[test]
+    fn test_report() {
+        let graph = PackageGraph {
+            names: vec!["a".into(), "b".into()],
+            dependencies: vec![BTreeSet::new(); 2],
+        };
+        let outcome = |succeeded: Vec<usize>, missing: Vec<usize>| Outcome {
+            succeeded,
+            missing,
+            ..Default::default()
+        };
+        assert!(report(&graph, "lint", &outcome(vec![0], vec![1])).is_ok());
+        let err = report(&graph, "lint", &outcome(vec![], vec![0, 1])).unwrap_err();
+        assert_eq!(err.to_string(), "No selected package defines a lint script");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel() {
+        let cancel = AtomicBool::new(false);
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            let dir = std::env::temp_dir();
+            assert!(!run_prefixed(&dir, "sleep 5; echo done", "p0", &cancel).unwrap());
+        });
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
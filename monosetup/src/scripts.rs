@@ -0,0 +1,493 @@
+use std::{collections::HashMap, error::Error, ops::Range};
+
+use glob::{MatchOptions, Pattern};
+use indexmap::IndexMap;
+
+use crate::config::NotestConfig;
+
+/// The suffix of the script variants that skip the tests.
+const NOTEST: &str = ":notest";
+
+/// A word of a script without its quotes, with its byte range in the script.
+type Word = (String, Range<usize>);
+
+/// Decides which steps of a script run tests.
+pub struct TestRules {
+    scripts: Vec<Pattern>,
+    commands: Vec<String>,
+}
+
+impl TestRules {
+    pub fn new(config: &NotestConfig) -> Result<Self, Box<dyn Error>> {
+        let scripts = config
+            .scripts
+            .iter()
+            .map(|p| Pattern::new(p).map_err(|e| format!("Invalid notest script pattern `{p}`: {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            scripts,
+            commands: config.commands.clone(),
+        })
+    }
+
+    fn is_test_script(&self, name: &str) -> bool {
+        self.scripts.iter().any(|p| p.matches(name))
+    }
+
+    /// Checks if `words` run a test program, looking through environment variables and wrappers
+    /// such as `npx`, `cross-env` or `yarn exec`.
+    fn is_test_command(&self, words: &[Word]) -> bool {
+        for (word, _) in words.iter().filter(|(w, _)| !w.starts_with('-') && !is_assignment(w)) {
+            let program = program_name(word);
+            if self.commands.iter().any(|c| c == program) {
+                return true;
+            }
+            if !["npx", "pnpx", "env", "cross-env", "yarn", "pnpm", "exec", "dlx"].contains(&program) {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// A command between `&&`, `||`, `;`, `&` or a line break. The commands of a pipeline are its
+/// stages.
+#[derive(Debug)]
+struct Step {
+    range: Range<usize>,
+    stages: Vec<Vec<Word>>,
+}
+
+/// Splits `script` into its steps. Operators inside quotes, parentheses and `$(...)` do not
+/// separate steps.
+fn steps(script: &str) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let mut stages: Vec<Vec<Word>> = vec![Vec::new()];
+    let mut word: Option<(String, usize)> = None;
+    let (mut quote, mut depth) = (None, 0);
+    let mut chars = script.char_indices().peekable();
+    let finish_step = |stages: &mut Vec<Vec<Word>>, steps: &mut Vec<Step>| {
+        let words: Vec<&Word> = stages.iter().flatten().collect();
+        if let (Some(first), Some(last)) = (words.first(), words.last()) {
+            let range = first.1.start..last.1.end;
+            steps.push(Step {
+                range,
+                stages: std::mem::replace(stages, vec![Vec::new()]),
+            });
+        }
+    };
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            let text = &mut word.as_mut().unwrap().0;
+            match c {
+                _ if c == q => quote = None,
+                '\\' if q == '"' => text.extend(chars.next().map(|(_, n)| n)),
+                _ => text.push(c),
+            }
+            continue;
+        }
+        // Redirections such as `2>&1` are part of the word
+        let redirect = script[..i].ends_with(['>', '<']);
+        let separator = depth == 0 && !redirect && matches!(c, '&' | '|' | ';' | '\n');
+        if c.is_whitespace() && depth == 0 || separator {
+            if let Some((text, start)) = word.take() {
+                stages.last_mut().unwrap().push((text, start..i));
+            }
+            if c == '|' && chars.peek().is_none_or(|(_, n)| *n != '|') {
+                stages.push(Vec::new());
+            } else if separator {
+                if matches!(c, '&' | '|') && chars.peek().is_some_and(|(_, n)| *n == c) {
+                    chars.next();
+                }
+                finish_step(&mut stages, &mut steps);
+            }
+            continue;
+        }
+        let text = &mut word.get_or_insert_with(|| (String::new(), i)).0;
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '\\' => text.extend(chars.next().map(|(_, n)| n)),
+            '(' => {
+                depth += 1;
+                text.push(c);
+            }
+            ')' => {
+                depth -= 1;
+                text.push(c);
+            }
+            _ => text.push(c),
+        }
+    }
+    if let Some((text, start)) = word.take() {
+        stages.last_mut().unwrap().push((text, start..script.len()));
+    }
+    finish_step(&mut stages, &mut steps);
+    steps
+}
+
+/// Checks if `word` sets an environment variable, e.g. `NODE_ENV=production`.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// The file name of a program, e.g. `mocha` for `./node_modules/.bin/mocha`.
+fn program_name(word: &str) -> &str {
+    word.rsplit(['/', '\\']).next().unwrap_or(word)
+}
+
+/// Scripts of the package a command runs.
+enum Call<'w> {
+    /// `npm run x`, `yarn x` or `npm test`, the latter is a `shorthand` without `run`
+    Script { word: &'w Word, shorthand: bool },
+    /// The scripts or script patterns of `npm-run-all`, `run-s` or `run-p`
+    List(Vec<&'w Word>),
+}
+
+fn call<'w>(words: &'w [Word], scripts: &IndexMap<String, String>) -> Option<Call<'w>> {
+    let start = words.iter().position(|(w, _)| !is_assignment(w))?;
+    let program = program_name(&words[start].0);
+    let mut args = words[start + 1..]
+        .iter()
+        .take_while(|(w, _)| w != "--")
+        .filter(|(w, _)| !w.starts_with('-'));
+    match program {
+        "npm" | "pnpm" | "yarn" => {
+            let first = args.next()?;
+            match first.0.as_str() {
+                "run" | "run-script" => args.next().map(|word| Call::Script { word, shorthand: false }),
+                "test" | "t" | "tst" => Some(Call::Script {
+                    word: first,
+                    shorthand: true,
+                }),
+                name if program != "npm" && scripts.contains_key(name) => Some(Call::Script {
+                    word: first,
+                    shorthand: false,
+                }),
+                _ => None,
+            }
+        }
+        "npm-run-all" | "run-s" | "run-p" => Some(Call::List(args.collect())),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resolved {
+    /// The script runs no tests
+    Unchanged,
+    /// The script only runs tests
+    OnlyTests,
+    /// The script has a `:notest` variant
+    Variant,
+}
+
+/// Follows the scripts a script runs and generates their `:notest` variants.
+struct Resolver<'a> {
+    scripts: &'a IndexMap<String, String>,
+    upstream: &'a dyn Fn(&str) -> bool,
+    rules: &'a TestRules,
+    resolved: HashMap<String, Resolved>,
+    variants: IndexMap<String, String>,
+}
+
+impl Resolver<'_> {
+    /// Resolves the script `name` and, with `hooks`, its `pre` and `post` scripts which npm and
+    /// yarn run around it.
+    fn resolve(&mut self, name: &str, hooks: bool) -> Resolved {
+        if self.rules.is_test_script(name) {
+            return Resolved::OnlyTests;
+        }
+        if (self.upstream)(&format!("{name}{NOTEST}")) {
+            return Resolved::Variant;
+        }
+        let scripts = self.scripts;
+        let Some(script) = scripts.get(name) else {
+            return Resolved::Unchanged;
+        };
+        if let Some(resolved) = self.resolved.get(name) {
+            return *resolved;
+        }
+        // Scripts running each other in a cycle are kept as they are
+        self.resolved.insert(name.into(), Resolved::Unchanged);
+        let body = self.rewrite(script);
+        let hooks: Vec<(String, Resolved)> = ["pre", "post"]
+            .iter()
+            .map(|h| format!("{h}{name}"))
+            .filter(|h| hooks && scripts.contains_key(h))
+            .map(|h| {
+                let resolved = self.resolve(&h, false);
+                (h, resolved)
+            })
+            .collect();
+        let resolved = match body {
+            None if hooks.iter().all(|(_, r)| *r == Resolved::Unchanged) => Resolved::Unchanged,
+            Some(body) if body.trim().is_empty() && hooks.iter().all(|(_, r)| *r == Resolved::OnlyTests) => {
+                Resolved::OnlyTests
+            }
+            body => {
+                // The variant runs the hooks of its own name
+                for (hook, resolved) in hooks {
+                    if resolved == Resolved::Unchanged {
+                        self.variants.insert(format!("{hook}{NOTEST}"), scripts[&hook].clone());
+                    }
+                }
+                self.variants
+                    .insert(format!("{name}{NOTEST}"), body.unwrap_or_else(|| script.clone()));
+                Resolved::Variant
+            }
+        };
+        self.resolved.insert(name.into(), resolved);
+        resolved
+    }
+
+    /// `script` without its test steps and with the scripts it runs replaced by their variants,
+    /// `None` if nothing changes.
+    fn rewrite(&mut self, script: &str) -> Option<String> {
+        let steps = steps(script);
+        let mut changed = false;
+        let mut kept: Vec<(usize, String)> = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            if step.stages.iter().any(|s| self.rules.is_test_command(s)) {
+                changed = true;
+                continue;
+            }
+            let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+            let mut only_tests = false;
+            if let [stage] = step.stages.as_slice() {
+                match call(stage, self.scripts) {
+                    Some(Call::Script { word, shorthand }) => match self.resolve(&word.0, true) {
+                        Resolved::OnlyTests => only_tests = true,
+                        Resolved::Variant if shorthand => {
+                            edits.push((word.1.clone(), format!("run {}{NOTEST}", word.0)))
+                        }
+                        Resolved::Variant => edits.push((word.1.clone(), format!("{}{NOTEST}", word.0))),
+                        Resolved::Unchanged => {}
+                    },
+                    Some(Call::List(words)) => {
+                        let mut all: Vec<Resolved> = Vec::new();
+                        for word in words {
+                            let names = self.expand(&word.0);
+                            let resolved: Vec<(String, Resolved)> = names
+                                .into_iter()
+                                .map(|n| {
+                                    let resolved = self.resolve(&n, true);
+                                    (n, resolved)
+                                })
+                                .collect();
+                            all.extend(resolved.iter().map(|(_, r)| *r));
+                            if resolved.iter().all(|(_, r)| *r == Resolved::Unchanged) {
+                                continue;
+                            }
+                            let list: Vec<String> = resolved
+                                .into_iter()
+                                .filter_map(|(n, r)| match r {
+                                    Resolved::OnlyTests => None,
+                                    Resolved::Variant => Some(format!("{n}{NOTEST}")),
+                                    Resolved::Unchanged => Some(n),
+                                })
+                                .collect();
+                            let mut range = word.1.clone();
+                            if list.is_empty() {
+                                range.start = script[..range.start].trim_end().len();
+                            }
+                            edits.push((range, list.join(" ")));
+                        }
+                        only_tests = !all.is_empty() && all.iter().all(|r| *r == Resolved::OnlyTests);
+                    }
+                    None => {}
+                }
+            }
+            if only_tests {
+                changed = true;
+                continue;
+            }
+            changed |= !edits.is_empty();
+            let mut text = script[step.range.clone()].to_string();
+            for (range, replacement) in edits.into_iter().rev() {
+                text.replace_range(
+                    range.start - step.range.start..range.end - step.range.start,
+                    &replacement,
+                );
+            }
+            kept.push((i, text));
+        }
+        if !changed {
+            return None;
+        }
+        let (Some(first), Some(last)) = (steps.first(), steps.last()) else {
+            return None;
+        };
+        let mut result = script[..first.range.start].to_string();
+        let mut previous: Option<usize> = None;
+        for (i, text) in kept {
+            // Of the separators around dropped steps, `;`, `&` and line breaks bind weaker than `&&`
+            // and `||` and are kept, otherwise the one before: `a; mocha && b` becomes `a; b`
+            if let Some(p) = previous {
+                let separators: Vec<&str> = (p..i)
+                    .map(|k| &script[steps[k].range.end..steps[k + 1].range.start])
+                    .collect();
+                let ends_list = |s: &str| s.contains([';', '\n']) || (s.contains('&') && !s.contains("&&"));
+                let separator = separators.iter().find(|s| ends_list(s)).unwrap_or(&separators[0]);
+                result.push_str(separator);
+            }
+            result.push_str(&text);
+            previous = Some(i);
+        }
+        result.push_str(&script[last.range.end..]);
+        Some(result)
+    }
+
+    /// The scripts matching an `npm-run-all` pattern, where `*` does not match `:`.
+    fn expand(&self, pattern: &str) -> Vec<String> {
+        let glob = match Pattern::new(&pattern.replace(':', "/")) {
+            Ok(glob) if pattern.contains(['*', '?', '[']) => glob,
+            _ => return vec![pattern.into()],
+        };
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        self.scripts
+            .keys()
+            .filter(|n| !n.ends_with(NOTEST) && glob.matches_with(&n.replace(':', "/"), options))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The `:notest` variants of `build` and of every script it runs, directly or through `npm run`,
+/// `npm-run-all` lists and `pre`/`post` hooks, without the steps `rules` consider tests. Scripts
+/// running no tests are kept as they are, `build:notest` is always generated. Variants the
+/// `upstream` package defines are used instead of being generated.
+pub fn notest_variants(
+    scripts: &IndexMap<String, String>,
+    upstream: &dyn Fn(&str) -> bool,
+    rules: &TestRules,
+) -> IndexMap<String, String> {
+    let mut resolver = Resolver {
+        scripts,
+        upstream,
+        rules,
+        resolved: HashMap::new(),
+        variants: IndexMap::new(),
+    };
+    match resolver.resolve("build", true) {
+        Resolved::Unchanged => {
+            let build = scripts.get("build").cloned().unwrap_or_default();
+            resolver.variants.insert(format!("build{NOTEST}"), build);
+        }
+        Resolved::OnlyTests => {
+            resolver.variants.insert(format!("build{NOTEST}"), String::new());
+        }
+        Resolved::Variant => {}
+    }
+    let mut variants = resolver.variants;
+    // In the order of the scripts they are generated from
+    variants.sort_by_cached_key(|name, _| scripts.get_index_of(name.trim_end_matches(NOTEST)));
+    variants
+}
+
+#[cfg(test)]
+mod test {
+    use indexmap::IndexMap;
+
+    use crate::config::NotestConfig;
+
+    use super::{notest_variants, steps, TestRules};
+
+    fn variants(scripts: &[(&str, &str)], upstream: &[&str]) -> Vec<(String, String)> {
+        let scripts: IndexMap<String, String> = scripts.iter().map(|(n, s)| (n.to_string(), s.to_string())).collect();
+        let rules = TestRules::new(&NotestConfig::default()).unwrap();
+        notest_variants(&scripts, &|name| upstream.contains(&name), &rules)
+            .into_iter()
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|(n, s)| (n.to_string(), s.to_string())).collect()
+    }
+
+    #[test]
+    fn test_steps() {
+        let words = |script: &str| {
+            steps(script)
+                .into_iter()
+                .map(|s| {
+                    s.stages
+                        .iter()
+                        .map(|w| w.iter().map(|(w, _)| w.as_str()).collect::<Vec<_>>().join(" "))
+                        .collect::<Vec<_>>()
+                        .join(" | ")
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            words(r#"tsc -p "tsconfig a.json" && echo 'a && b'; mocha 2>&1 | tee out || (cd x && jest)"#),
+            vec![
+                "tsc -p tsconfig a.json",
+                "echo a && b",
+                "mocha 2>&1 | tee out",
+                "(cd x && jest)"
+            ]
+        );
+        assert_eq!(words("a &\nb"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_notest_variants() {
+        assert_eq!(
+            variants(
+                &[
+                    (
+                        "build",
+                        "npm run build:node && npm run bundle; node ./scripts/latest.js"
+                    ),
+                    ("build:node", "tsc -p tsconfig.test.json"),
+                    ("bundle", "run-s bundle:* && npx karma start"),
+                    ("bundle:lib", "webpack"),
+                    ("bundle:test", "webpack --config webpack.test.config.js"),
+                ],
+                &[]
+            ),
+            pairs(&[
+                (
+                    "build:notest",
+                    "npm run build:node && npm run bundle:notest; node ./scripts/latest.js"
+                ),
+                ("bundle:notest", "run-s bundle:lib"),
+            ])
+        );
+        assert_eq!(
+            variants(
+                &[
+                    ("prebuild", "rimraf dist"),
+                    ("build", "yarn compile"),
+                    ("postbuild", "NODE_ENV=test mocha || jest"),
+                    ("compile", "tsc"),
+                    ("precompile", "yarn test"),
+                ],
+                &[]
+            ),
+            pairs(&[
+                ("prebuild:notest", "rimraf dist"),
+                ("build:notest", "yarn compile:notest"),
+                ("compile:notest", "tsc"),
+            ])
+        );
+        assert_eq!(
+            variants(
+                &[("build", "tsc && npm run bundle"), ("bundle", "webpack && npm test")],
+                &["bundle:notest"]
+            ),
+            pairs(&[("build:notest", "tsc && npm run bundle:notest")])
+        );
+        assert_eq!(variants(&[("build", "tsc")], &[]), pairs(&[("build:notest", "tsc")]));
+        assert_eq!(
+            variants(&[("build", "tsc; mocha && webpack || jest; echo done")], &[]),
+            pairs(&[("build:notest", "tsc; webpack; echo done")])
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::{Component, Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+
+use crate::{diagnostics::Diagnostic, graph::PackageGraph, json_edit::JsonDocument, workspace::Workspace};
+
+/// Links the TypeScript projects of the packages following their internal dependencies: each
+/// tsconfig.json references the projects it depends on, which become `composite`, and maps their
+/// names to their sources in `paths`. The root tsconfig.json references every project, so `tsc -b`
+/// builds the monorepo incrementally.
+pub fn update_tsconfigs(workspace: &mut Workspace) -> Result<(), Box<dyn Error>> {
+    let graph = PackageGraph::new(workspace);
+    let order = graph.topological_order()?;
+    let mut configs = Vec::with_capacity(workspace.packages.len());
+    for package in workspace.packages.iter() {
+        configs.push(match &package.tsconfig {
+            Some(file) => Some(parse(&file.path, &file.content)?),
+            None => None,
+        });
+    }
+    let dirs: Vec<PathBuf> = workspace.packages.iter().map(|p| p.dir.clone()).collect();
+    let projects = |i: usize| graph.dependencies[i].iter().copied().filter(|d| configs[*d].is_some());
+    let referenced: BTreeSet<usize> = (0..dirs.len())
+        .filter(|i| configs[*i].is_some())
+        .flat_map(projects)
+        .collect();
+    let sources: Vec<Option<PathBuf>> = (0..dirs.len())
+        .map(|i| source_dir(&dirs[i], configs[i].as_ref()))
+        .collect();
+    for (i, package) in workspace.packages.iter_mut().enumerate() {
+        let (Some(file), Some(config)) = (package.tsconfig.as_mut(), &configs[i]) else {
+            continue;
+        };
+        let mut document = JsonDocument::parse(file.content.as_str())?;
+        if referenced.contains(&i) {
+            document.set(&["compilerOptions", "composite"], &true.into());
+        }
+        // References to projects outside of the workspace are kept
+        let mut references: Vec<Value> = config["references"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|r| {
+                !r["path"]
+                    .as_str()
+                    .is_some_and(|p| is_project_of(&dirs[i].join(p), &dirs))
+            })
+            .cloned()
+            .collect();
+        references.extend(projects(i).map(|d| json!({ "path": relative_path(&dirs[i], &dirs[d]) })));
+        if !references.is_empty() || !config["references"].is_null() {
+            document.set(&["references"], &Value::Array(references));
+        }
+        // `paths` are relative to the `baseUrl` if there is one
+        let base = match config["compilerOptions"]["baseUrl"].as_str() {
+            Some(base_url) => dirs[i].join(base_url),
+            None => dirs[i].clone(),
+        };
+        for d in projects(i) {
+            let Some(source) = &sources[d] else {
+                continue;
+            };
+            let name = &graph.names[d];
+            let target = relative_path(&base, source);
+            document.set(&["compilerOptions", "paths", name], &json!([target]));
+            document.set(
+                &["compilerOptions", "paths", &format!("{name}/*")],
+                &json!([format!("{target}/*")]),
+            );
+        }
+        file.content = document.as_str().to_string();
+    }
+    let members = workspace.members();
+    let references: Vec<Value> = order
+        .into_iter()
+        .filter(|i| configs[*i].is_some())
+        .map(|i| json!({ "path": members[i] }))
+        .collect();
+    if references.is_empty() {
+        return Ok(());
+    }
+    let content = match workspace.file("tsconfig.json") {
+        Some(existing) => {
+            parse(&existing.path, &existing.content)?;
+            let mut document = JsonDocument::parse(existing.content.as_str())?;
+            document.set(&["references"], &Value::Array(references));
+            document.as_str().to_string()
+        }
+        // A solution file that only builds the referenced projects
+        None => serde_json::to_string_pretty(&json!({ "files": [], "references": references }))? + "\n",
+    };
+    workspace.set_file("tsconfig.json", content);
+    Ok(())
+}
+
+/// Parses a tsconfig.json, which may have comments and trailing commas.
+fn parse(path: &Path, content: &str) -> Result<Value, Diagnostic> {
+    JsonDocument::parse(content).map_err(|e| {
+        Diagnostic::new("Failed to parse tsconfig.json").at_line(path, content, e.line, e.column, e.message)
+    })?;
+    json5::from_str(content).map_err(|e| Diagnostic::new(format!("Failed to parse tsconfig.json: {e}")).in_file(path))
+}
+
+/// The directory with the sources of a project: its `rootDir`, or `src` if it exists.
+fn source_dir(dir: &Path, config: Option<&Value>) -> Option<PathBuf> {
+    if let Some(root_dir) = config?["compilerOptions"]["rootDir"].as_str() {
+        return Some(normalize(&dir.join(root_dir)));
+    }
+    Some(dir.join("src")).filter(|src| src.is_dir())
+}
+
+/// Checks if a reference `path` points at one of the package `dirs` or its tsconfig.json.
+fn is_project_of(path: &Path, dirs: &[PathBuf]) -> bool {
+    let path = normalize(path);
+    let dir = if path.ends_with("tsconfig.json") {
+        path.parent().unwrap()
+    } else {
+        path.as_path()
+    };
+    dirs.iter().any(|d| d == dir)
+}
+
+/// Resolves `.` and `..` without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// The path from the directory `from` to `to` as written in a tsconfig.json, e.g. `../cns-transport`.
+pub fn relative_path(from: &Path, to: &Path) -> String {
+    let (from, to) = (normalize(from), normalize(to));
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts: Vec<String> = vec!["..".into(); from.components().count() - common];
+    parts.extend(
+        to.components()
+            .skip(common)
+            .map(|c| c.as_os_str().to_string_lossy().into_owned()),
+    );
+    match parts.first().map(String::as_str) {
+        None => ".".into(),
+        Some("..") => parts.join("/"),
+        Some(_) => format!("./{}", parts.join("/")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use serde_json::{json, Value};
+
+    use crate::workspace::Workspace;
+
+    use super::{relative_path, update_tsconfigs};
+
+    #[test]
+    fn test_relative_path() {
+        let path = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(path("/m/packages/a", "/m/packages/b/src"), "../b/src");
+        assert_eq!(path("/m/packages/a/./", "/m/packages/a/src"), "./src");
+        assert_eq!(path("/m/packages/a", "/m/packages/a"), ".");
+    }
+
+    #[test]
+    fn test_update_tsconfigs() {
+        let root = std::env::temp_dir().join(format!("monosetup-tsconfig-{}", std::process::id()));
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("packages/a/package.json", r#"{ "name": "a", "version": "1.0.0" }"#);
+        write(
+            "packages/a/tsconfig.json",
+            "{\n  // the library\n  \"compilerOptions\": { \"rootDir\": \"./lib-src\" }\n}\n",
+        );
+        write(
+            "packages/b/package.json",
+            r#"{ "name": "b", "version": "1.0.0", "dependencies": { "a": "1.0.0" } }"#,
+        );
+        write(
+            "packages/b/tsconfig.json",
+            r#"{ "compilerOptions": {}, "references": [{ "path": "../../tools" }, { "path": "../a/tsconfig.json" }] }"#,
+        );
+        let mut workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+        update_tsconfigs(&mut workspace).unwrap();
+        let a = &workspace.packages[0].tsconfig.as_ref().unwrap().content;
+        assert!(a.starts_with("{\n  // the library\n") && a.contains("\"composite\": true"));
+        assert_eq!(
+            workspace.packages[1].tsconfig.as_ref().unwrap().content,
+            r#"{ "compilerOptions": { "paths": { "a": ["../a/lib-src"], "a/*": ["../a/lib-src/*"] } }, "references": [{ "path": "../../tools" }, { "path": "../a" }] }"#
+        );
+        let b: Value = json5::from_str(&workspace.packages[1].tsconfig.as_ref().unwrap().content).unwrap();
+        assert_eq!(b["references"], json!([{ "path": "../../tools" }, { "path": "../a" }]));
+        assert_eq!(
+            b["compilerOptions"]["paths"],
+            json!({ "a": ["../a/lib-src"], "a/*": ["../a/lib-src/*"] })
+        );
+        let solution: Value = serde_json::from_str(&workspace.files[0].content).unwrap();
+        assert_eq!(
+            solution,
+            json!({ "files": [], "references": [{ "path": "packages/a" }, { "path": "packages/b" }] })
+        );
+        // An existing solution file is loaded with the workspace and keeps its comments
+        write("tsconfig.json", "{\n  // the monorepo\n  \"files\": []\n}\n");
+        let mut workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+        update_tsconfigs(&mut workspace).unwrap();
+        let solution = &workspace.file("tsconfig.json").unwrap().content;
+        assert!(solution.starts_with("{\n  // the monorepo\n") && solution.contains("\"path\": \"packages/b\""), "{solution}");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::{
+    affected::package_of,
+    cache::{is_ignored, is_temp_file},
+    graph::PackageGraph,
+    runner,
+    workspace::Workspace,
+};
+
+/// How long the sources have to stay unchanged before a rebuild starts.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watches the sources of every package and rebuilds the changed packages and their dependents.
+/// Changes during a build cancel it, the next build includes the packages it did not finish.
+pub fn watch(
+    workspace: &Workspace,
+    graph: &PackageGraph,
+    outputs: &[String],
+    jobs: usize,
+) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Builds read the sources, only modifications count
+        match event {
+            Ok(event) if !event.kind.is_access() => {
+                let _ = tx.send(event.paths);
+            }
+            _ => {}
+        }
+    })?;
+    for package in workspace.packages.iter() {
+        watcher.watch(&package.dir, RecursiveMode::Recursive)?;
+    }
+    println!("Watching {} packages for changes", workspace.packages.len());
+    let dirs: Vec<&Path> = workspace.packages.iter().map(|p| p.dir.as_path()).collect();
+    thread::scope(|s| {
+        let mut running: Option<(thread::ScopedJoinHandle<bool>, Arc<AtomicBool>, BTreeSet<usize>)> = None;
+        loop {
+            let changed = changed_packages(&dirs, outputs, &next_changes(&rx, DEBOUNCE)?);
+            if changed.is_empty() {
+                continue;
+            }
+            let mut selected = graph.with_dependents(&changed);
+            if let Some((handle, cancel, previous)) = running.take() {
+                if !handle.is_finished() {
+                    println!("Sources changed, cancelling the running build");
+                }
+                cancel.store(true, Ordering::Relaxed);
+                if !handle.join().unwrap() {
+                    selected.extend(previous);
+                }
+            }
+            let names: Vec<&str> = changed.iter().map(|i| graph.names[*i].as_str()).collect();
+            println!("Changed: {}", names.join(", "));
+            let cancel = Arc::new(AtomicBool::new(false));
+            let (packages, flag) = (selected.clone(), cancel.clone());
+            let handle = s.spawn(move || {
+                let result = runner::run_script(workspace, graph, &packages, "build:notest", jobs, None, &flag);
+                match result {
+                    Ok(()) => true,
+                    Err(_) if flag.load(Ordering::Relaxed) => false,
+                    Err(e) => {
+                        println!("error: {e}");
+                        // Failed packages are rebuilt once their sources change
+                        true
+                    }
+                }
+            });
+            running = Some((handle, cancel, selected));
+        }
+    })
+}
+
+/// Waits for changes and collects them until none arrive for the `debounce` time.
+fn next_changes(rx: &Receiver<Vec<PathBuf>>, debounce: Duration) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut paths = rx.recv()?;
+    loop {
+        match rx.recv_timeout(debounce) {
+            Ok(more) => paths.extend(more),
+            Err(RecvTimeoutError::Timeout) => return Ok(paths),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The packages in `dirs` containing the changed `paths`, changes to ignored directories such as
+/// the build outputs are left out so a build does not trigger the next one, as are editor temp files.
+fn changed_packages(dirs: &[&Path], outputs: &[String], paths: &[PathBuf]) -> BTreeSet<usize> {
+    let mut changed = BTreeSet::new();
+    for path in paths {
+        let Some(i) = package_of(dirs, path) else {
+            continue;
+        };
+        if path.file_name().is_some_and(|name| is_temp_file(&name.to_string_lossy())) {
+            continue;
+        }
+        // The last component is only a directory name if the path is not a file, e.g. a removed output
+        let relative = path.strip_prefix(dirs[i]).unwrap();
+        let dirs = if path.is_file() {
+            relative.parent()
+        } else {
+            Some(relative)
+        };
+        let ignored = dirs
+            .into_iter()
+            .flat_map(|p| p.components())
+            .any(|c| is_ignored(&c.as_os_str().to_string_lossy(), outputs));
+        if !ignored {
+            changed.insert(i);
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use super::{changed_packages, next_changes};
+
+    #[test]
+    fn test_changed_packages() {
+        let root = std::env::temp_dir().join(format!("monosetup-watch-{}", std::process::id()));
+        let (a, nested, b) = (root.join("packages/a"), root.join("packages/a/nested"), root.join("packages/b"));
+        for dir in [&a, &nested, &b] {
+            fs::create_dir_all(dir.join("src")).unwrap();
+        }
+        fs::create_dir_all(a.join("dist")).unwrap();
+        let dirs: Vec<&Path> = vec![&a, &nested, &b];
+        let outputs = ["dist".to_string()];
+        let changed = |files: &[&Path]| {
+            let paths: Vec<PathBuf> = files.iter().map(|f| f.to_path_buf()).collect();
+            for path in paths.iter() {
+                fs::write(path, "").unwrap();
+            }
+            changed_packages(&dirs, &outputs, &paths)
+        };
+        assert_eq!(changed(&[&nested.join("src/index.ts")]), [1].into());
+        assert_eq!(changed(&[&a.join("src/index.ts"), &b.join("package.json")]), [0, 2].into());
+        assert!(changed(&[&a.join("dist/index.js"), &root.join("README.md")]).is_empty());
+        // Swap, backup and probe files of editors
+        let temp = [a.join("src/.index.ts.swp"), a.join("src/index.ts~"), a.join("src/4913"), b.join("src/.#index.ts")];
+        assert!(changed(&temp.iter().map(PathBuf::as_path).collect::<Vec<_>>()).is_empty());
+        // A removed output directory is not a source
+        assert!(changed_packages(&dirs, &outputs, &[b.join("dist")]).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_next_changes_debounces() {
+        let (tx, rx) = mpsc::channel();
+        let sender = thread::spawn(move || {
+            for name in ["a", "b", "c"] {
+                tx.send(vec![PathBuf::from(name)]).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+            // Arrives after the quiet period, it starts the next batch
+            thread::sleep(Duration::from_millis(300));
+            tx.send(vec![PathBuf::from("d")]).unwrap();
+            thread::sleep(Duration::from_millis(300));
+        });
+        let debounce = Duration::from_millis(150);
+        assert_eq!(next_changes(&rx, debounce).unwrap(), ["a", "b", "c"].map(PathBuf::from));
+        assert_eq!(next_changes(&rx, debounce).unwrap(), [PathBuf::from("d")]);
+        sender.join().unwrap();
+        assert!(next_changes(&rx, debounce).is_err());
+    }
+}
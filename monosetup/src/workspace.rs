@@ -0,0 +1,379 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use glob::{glob_with, MatchOptions};
+use serde_json::Value;
+
+use crate::{
+    bundler::BUNDLERS,
+    diagnostics::Diagnostic,
+    CONFIG,
+    lockfile::Lockfile,
+    manifest::{ManifestFile, PackageManifest},
+    node_modules::is_config,
+    originals,
+};
+
+/// A text file held in memory, it is written on commit if `content` differs from the file on disk.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub content: String,
+    /// The content on disk, `None` if the file does not exist yet
+    original: Option<String>,
+}
+
+impl SourceFile {
+    /// Reads `path`, returns `None` if there is no such file.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(Self {
+                path: path.to_path_buf(),
+                original: Some(content.clone()),
+                content,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A file with `content` that replaces whatever is at `path` on commit.
+    pub fn create(path: &Path, content: String) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            content,
+            original: fs::read_to_string(path).ok(),
+        }
+    }
+}
+
+/// A package of the monorepo with every file monosetup reads or rewrites.
+#[derive(Debug)]
+pub struct Package {
+    pub dir: PathBuf,
+    pub manifest: ManifestFile,
+    /// The manifest as it was before monosetup first rewrote it, `None` if it was never rewritten
+    pub upstream: Option<PackageManifest>,
+    pub lock: Option<Lockfile>,
+    /// The configs of the bundlers in `bundler::BUNDLERS`
+    pub bundler_configs: Vec<SourceFile>,
+    pub tsconfig: Option<SourceFile>,
+    /// Other config files at the root of the package, see `node_modules::is_config`
+    pub configs: Vec<SourceFile>,
+}
+
+impl Package {
+    fn load(root: &Path, dir: &Path) -> Result<Self, Diagnostic> {
+        let manifest = ManifestFile::load(&dir.join("package.json"))?;
+        let upstream = originals::load(root, &manifest.path).and_then(|content| serde_json::from_str(&content).ok());
+        let lock = Lockfile::load(dir)
+            .map_err(|e| Diagnostic::new(format!("Failed to read the lockfile: {e}")).in_file(dir))?;
+        let mut bundler_configs = Vec::new();
+        for name in BUNDLERS.iter().flat_map(|b| b.config_files()) {
+            let path = dir.join(name);
+            let config = SourceFile::load(&path)
+                .map_err(|e| Diagnostic::new(format!("Failed to read the bundler config: {e}")).in_file(&path))?;
+            bundler_configs.extend(config);
+        }
+        let tsconfig = dir.join("tsconfig.json");
+        let tsconfig = SourceFile::load(&tsconfig)
+            .map_err(|e| Diagnostic::new(format!("Failed to read tsconfig.json: {e}")).in_file(&tsconfig))?;
+        let configs = load_configs(dir).map_err(|e| Diagnostic::new(format!("Failed to read the configs: {e}")).in_file(dir))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest,
+            upstream,
+            lock,
+            bundler_configs,
+            tsconfig,
+            configs,
+        })
+    }
+}
+
+fn load_configs(dir: &Path) -> io::Result<Vec<SourceFile>> {
+    let mut configs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_config(&path.file_name().unwrap_or_default().to_string_lossy()) {
+            configs.extend(SourceFile::load(&path)?);
+        }
+    }
+    configs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(configs)
+}
+
+/// The monorepo loaded into memory. Transformations only change the model, `commit` writes all
+/// changed files at once so a failure never leaves a half rewritten monorepo behind.
+#[derive(Debug)]
+pub struct Workspace {
+    pub root: PathBuf,
+    pub packages: Vec<Package>,
+    /// Files of the monorepo root, the ones monosetup reads are loaded with the packages
+    pub files: Vec<SourceFile>,
+}
+
+impl Workspace {
+    /// Loads the monorepo monosetup runs in with the configured workspace patterns.
+    pub fn current() -> Result<Self, Box<dyn Error>> {
+        let patterns = CONFIG.get().unwrap().workspace_patterns();
+        Self::load(&fs::canonicalize("../")?, &patterns)
+    }
+
+    /// Loads every package matching the workspace `patterns`, each on its own thread.
+    pub fn load(root: &Path, patterns: &[String]) -> Result<Self, Box<dyn Error>> {
+        let dirs = discover(root, patterns)?;
+        let packages = thread::scope(|s| {
+            let handles: Vec<_> = dirs.iter().map(|dir| s.spawn(|| Package::load(root, dir))).collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        let tsconfig = root.join("tsconfig.json");
+        let files = SourceFile::load(&tsconfig)
+            .map_err(|e| Diagnostic::new(format!("Failed to read tsconfig.json: {e}")).in_file(&tsconfig))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            packages,
+            files: files.into_iter().collect(),
+        })
+    }
+
+    /// The package directories relative to the root, as they are listed in `workspaces`.
+    pub fn members(&self) -> Vec<String> {
+        self.packages
+            .iter()
+            .filter_map(|p| p.dir.strip_prefix(&self.root).ok())
+            .map(|dir| {
+                let parts: Vec<_> = dir.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                parts.join("/")
+            })
+            .collect()
+    }
+
+    /// A file of the monorepo root, if it was loaded or set.
+    pub fn file(&self, name: &str) -> Option<&SourceFile> {
+        let path = self.root.join(name);
+        self.files.iter().find(|f| f.path == path)
+    }
+
+    /// Adds or replaces a file of the monorepo root.
+    pub fn set_file(&mut self, name: &str, content: String) {
+        let path = self.root.join(name);
+        match self.files.iter_mut().find(|f| f.path == path) {
+            Some(file) => file.content = content,
+            None => self.files.push(SourceFile::create(&path, content)),
+        }
+    }
+
+    /// Writes every changed file at once, see `write_files`. The upstream version of each file is
+    /// kept for `eject` before it is first rewritten. Returns the written paths.
+    pub fn commit(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut changes = Vec::new();
+        for package in self.packages.iter() {
+            if let Some(content) = package.manifest.render()? {
+                changes.push((package.manifest.path.clone(), Some(package.manifest.content()), content));
+            }
+            let files = package.bundler_configs.iter().chain(package.tsconfig.iter());
+            for file in files.chain(package.configs.iter()) {
+                changes.push((file.path.clone(), file.original.as_deref(), file.content.clone()));
+            }
+        }
+        for file in self.files.iter() {
+            changes.push((file.path.clone(), file.original.as_deref(), file.content.clone()));
+        }
+        changes.retain(|(_, original, content)| *original != Some(content.as_str()));
+        originals::record(&self.root, &changes)?;
+        write_files(&changes)?;
+        Ok(changes.into_iter().map(|(path, _, _)| path).collect())
+    }
+}
+
+/// Finds the package directories matching the glob `patterns` relative to `root`. Repos that are
+/// multi-package themselves are replaced by their members, directories without a package.json
+/// are skipped with a notice. Finding no package at all is an error, monosetup most likely runs
+/// in the wrong directory.
+pub fn discover(root: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut dirs = BTreeSet::new();
+    discover_in(root, root, patterns, &mut dirs)?;
+    if dirs.is_empty() {
+        let searched: Vec<String> = patterns.iter().map(|p| root.join(p).display().to_string()).collect();
+        return Err(format!("No packages found in {}", searched.join(", ")).into());
+    }
+    Ok(dirs.into_iter().collect())
+}
+
+fn discover_in(
+    root: &Path,
+    base: &Path,
+    patterns: &[String],
+    dirs: &mut BTreeSet<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let options = MatchOptions {
+        require_literal_leading_dot: true,
+        ..MatchOptions::new()
+    };
+    for pattern in patterns {
+        let paths = glob_with(&base.join(pattern).to_string_lossy(), options)
+            .map_err(|e| format!("Invalid workspace pattern `{pattern}`: {e}"))?;
+        for dir in paths {
+            let dir = dir?;
+            if !dir.is_dir() || dir == base || dir.components().any(|c| c.as_os_str() == "node_modules") {
+                continue;
+            }
+            if !dir.join("package.json").is_file() {
+                println!(
+                    "Skipping {}: no package.json",
+                    dir.strip_prefix(root).unwrap_or(&dir).display()
+                );
+                continue;
+            }
+            match nested_workspaces(&dir) {
+                Some(nested) => discover_in(root, &dir, &nested, dirs)?,
+                None => {
+                    dirs.insert(dir);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The workspace patterns of a multi-package repo, from the `workspaces` of its package.json or
+/// the `packages` of its lerna.json.
+fn nested_workspaces(dir: &Path) -> Option<Vec<String>> {
+    let read = |file: &str| -> Option<Value> { serde_json::from_str(&fs::read_to_string(dir.join(file)).ok()?).ok() };
+    let mut manifest = read("package.json")?;
+    let patterns = match manifest["workspaces"].take() {
+        Value::Object(mut workspaces) => workspaces.remove("packages")?,
+        Value::Null => read("lerna.json")?["packages"].take(),
+        patterns => patterns,
+    };
+    let patterns = patterns.as_array()?;
+    Some(patterns.iter().filter_map(|p| Some(p.as_str()?.to_string())).collect())
+}
+
+/// Replaces each `(path, original, content)` by writing a temporary file next to it and renaming
+/// it over the original. If any step fails the files already replaced are restored to `original`,
+/// or removed if they did not exist.
+pub fn write_files(changes: &[(PathBuf, Option<&str>, String)]) -> Result<(), Box<dyn Error>> {
+    let mut staged = Vec::with_capacity(changes.len());
+    for (path, _, content) in changes.iter() {
+        let temp = temp_path(path);
+        if let Err(e) = fs::write(&temp, content) {
+            discard(&staged);
+            return Err(format!("Failed to write {}: {e}", temp.display()).into());
+        }
+        staged.push(temp);
+    }
+    for (i, ((path, _, _), temp)) in changes.iter().zip(staged.iter()).enumerate() {
+        if let Err(e) = fs::rename(temp, path) {
+            discard(&staged[i..]);
+            for (path, original, _) in changes[..i].iter().rev() {
+                let restored = match original {
+                    Some(original) => fs::write(path, original),
+                    None => fs::remove_file(path),
+                };
+                if let Err(e) = restored {
+                    println!("Failed to restore {}: {e}", path.display());
+                }
+            }
+            return Err(format!("Failed to replace {}: {e}, no files were changed", path.display()).into());
+        }
+    }
+    Ok(())
+}
+
+/// The temporary file `path` is staged in, it lives in the same directory so the rename is atomic.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.monosetup-tmp"))
+}
+
+fn discard(staged: &[PathBuf]) {
+    for temp in staged {
+        let _ = fs::remove_file(temp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{discover, Workspace};
+
+    #[test]
+    fn test_commit_rolls_back() {
+        let root = std::env::temp_dir().join(format!("monosetup-workspace-{}", std::process::id()));
+        let manifest = "{\n  \"name\": \"a\",\n  \"version\": \"1.0.0\"\n}\n";
+        fs::create_dir_all(root.join("packages/a")).unwrap();
+        fs::write(root.join("packages/a/package.json"), manifest).unwrap();
+
+        let mut workspace = Workspace::load(&root, &["packages/*".into()]).unwrap();
+        assert_eq!(workspace.packages.len(), 1);
+        workspace.packages[0].manifest.manifest.version = "1.1.0".into();
+        // Renaming a file over a non empty directory fails after the manifest was replaced
+        fs::create_dir_all(root.join("package.json/x")).unwrap();
+        workspace.set_file("package.json", "{}\n".into());
+        assert!(workspace.commit().is_err());
+        assert_eq!(
+            fs::read_to_string(root.join("packages/a/package.json")).unwrap(),
+            manifest
+        );
+        assert_eq!(fs::read_dir(root.join("packages/a")).unwrap().count(), 1);
+
+        fs::remove_dir_all(root.join("package.json")).unwrap();
+        let written = workspace.commit().unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(fs::read_to_string(root.join("packages/a/package.json"))
+            .unwrap()
+            .contains("1.1.0"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_discover() {
+        let root = std::env::temp_dir().join(format!("monosetup-discover-{}", std::process::id()));
+        let manifest = |dir: &str, content: &str| {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("package.json"), content).unwrap();
+        };
+        manifest("packages/a", "{}");
+        manifest("packages/multi", r#"{ "workspaces": { "packages": ["libs/*"] } }"#);
+        manifest("packages/multi/libs/x", "{}");
+        manifest("packages/lerna", "{}");
+        fs::write(
+            root.join("packages/lerna/lerna.json"),
+            r#"{ "packages": ["modules/*"] }"#,
+        )
+        .unwrap();
+        manifest("packages/lerna/modules/y", "{}");
+        manifest("apps/web", "{}");
+        fs::create_dir_all(root.join("packages/empty")).unwrap();
+        fs::create_dir_all(root.join("packages/.git")).unwrap();
+
+        let dirs = discover(&root, &["packages/*".into(), "apps/*".into()]).unwrap();
+        let dirs: Vec<_> = dirs
+            .iter()
+            .map(|d| d.strip_prefix(&root).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            dirs,
+            [
+                "apps/web",
+                "packages/a",
+                "packages/lerna/modules/y",
+                "packages/multi/libs/x"
+            ]
+        );
+        let err = discover(&root, &["libs/*".into()]).unwrap_err();
+        assert_eq!(err.to_string(), format!("No packages found in {}", root.join("libs/*").display()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}